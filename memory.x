@@ -1,6 +1,7 @@
 MEMORY
 {
-  FLASH : ORIGIN = 0x08000000, LENGTH = 512K
+  /* The last two 128K sectors are reserved for src/scrobble.rs and src/settings.rs */
+  FLASH : ORIGIN = 0x08000000, LENGTH = 256K
   RAM : ORIGIN = 0x20000000, LENGTH = 128K
 }
 
@@ -0,0 +1,117 @@
+//! The commands applying the chosen A2DP role and audio input, limited to
+//! what the fitted module supports.
+
+use alloc::{vec, vec::Vec};
+use defmt::{info, Format};
+
+use crate::{
+    feasycom_capabilities::{Capabilities, CommandGate, A2DP_SOURCE},
+    feasycom_protocol::{command, indication::A2dpRole},
+};
+
+/// I2S slave, 44.1 kHz, 16 bit, see `AT+I2SCFG` in the module manual.
+const I2S_CONFIG: u8 = 0;
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Format)]
+pub enum AudioInput {
+    I2s,
+    Spdif,
+}
+
+/// The commands applying the persisted role, written once the module is up
+/// so a transmitter reconnects to the last chosen headphones on boot. A
+/// module without A2DP source is always a receiver, so gets none, and
+/// commands it does not support are left out.
+pub fn a2dp_source_commands(
+    a2dp_role: A2dpRole,
+    audio_input: AudioInput,
+    a2dp_sink: Option<&str>,
+    capabilities: &Capabilities,
+) -> Vec<Vec<u8>> {
+    if !capabilities.supports(A2DP_SOURCE) {
+        return Vec::new();
+    }
+
+    if a2dp_role == A2dpRole::Sink {
+        return vec![command::A2dpRole::new().slave().as_bytes().to_vec()];
+    }
+
+    let mut commands = vec![command::A2dpRole::new().master().as_bytes().to_vec()];
+
+    match audio_input {
+        AudioInput::I2s => {
+            commands.push(
+                command::SpdifCfg::new()
+                    .enable_spdif(false)
+                    .as_bytes()
+                    .to_vec(),
+            );
+            commands.push(
+                command::I2sCfg::new()
+                    .configure_i2s_pcm(I2S_CONFIG)
+                    .as_bytes()
+                    .to_vec(),
+            );
+        }
+        AudioInput::Spdif => {
+            commands.push(
+                command::SpdifCfg::new()
+                    .enable_spdif(true)
+                    .as_bytes()
+                    .to_vec(),
+            );
+        }
+    }
+
+    if let Some(a2dp_sink) = a2dp_sink {
+        info!("reconnecting to {}", a2dp_sink);
+
+        commands.push(command::A2dpConn::new().mac(a2dp_sink).as_bytes().to_vec());
+    }
+
+    commands.retain(|command| capabilities.gate(command) != CommandGate::Reject);
+    commands
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::feasycom_protocol::indication::Ver;
+
+    fn capabilities(ver: &[u8]) -> Capabilities {
+        Capabilities::of(&Ver::try_from(ver).unwrap())
+    }
+
+    #[test]
+    fn receivers_get_no_role_commands() {
+        let capabilities = capabilities(b"FSC-BT1006A-V3.1.0");
+
+        for a2dp_role in [A2dpRole::Sink, A2dpRole::Source] {
+            assert!(
+                a2dp_source_commands(a2dp_role, AudioInput::I2s, None, &capabilities).is_empty()
+            );
+        }
+    }
+
+    #[test]
+    fn unsupported_inputs_are_left_out() {
+        let capabilities = capabilities(b"FSC-BT1026-V3.1.0");
+        let commands = a2dp_source_commands(
+            A2dpRole::Source,
+            AudioInput::Spdif,
+            Some("00:11:22:33:44:55"),
+            &capabilities,
+        );
+
+        assert_eq!(
+            commands,
+            [
+                command::A2dpRole::new().master().as_bytes().to_vec(),
+                command::A2dpConn::new()
+                    .mac("00:11:22:33:44:55")
+                    .as_bytes()
+                    .to_vec(),
+            ]
+        );
+    }
+}
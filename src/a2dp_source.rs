@@ -0,0 +1,139 @@
+//! Switching the module between headphones receiver and transmitter, over
+//! SPP one request per line:
+//!
+//! ```text
+//! SOURCE I2S | SOURCE SPDIF   transmit the given input
+//! SINK                        receive from a phone
+//! SCAN | SCAN STOP            list headphones in range as SCAN <mac> <rssi> <name>
+//! CONNECT <mac>               connect to and remember the headphones
+//! ```
+
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+use defmt::{error, Format};
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::signal::Signal;
+
+use crate::{
+    a2dp_setup::{a2dp_source_commands, AudioInput},
+    app_state::app_state_with,
+    command_queue::{command_send, CommandPriority, CommandResult},
+    feasycom_protocol::{
+        command,
+        indication::{A2dpRole, ScanResult},
+    },
+    settings::{settings_load, settings_store, Settings},
+    spp::spp_send,
+};
+
+static A2DP_SOURCE_REQUEST_SIGNAL: Signal<ThreadModeRawMutex, A2dpSourceRequest> = Signal::new();
+
+pub fn a2dp_source_request(request: A2dpSourceRequest) {
+    A2DP_SOURCE_REQUEST_SIGNAL.signal(request);
+}
+
+async fn a2dp_source_request_get() -> A2dpSourceRequest {
+    A2DP_SOURCE_REQUEST_SIGNAL.wait().await
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Format)]
+pub enum A2dpSourceRequest {
+    EnableSource(AudioInput),
+    EnableSink,
+    StartScan,
+    StopScan,
+    Connect(String),
+}
+
+/// The commands applying the persisted role to the fitted module.
+pub fn a2dp_source_setup(settings: &Settings) -> Vec<Vec<u8>> {
+    a2dp_source_commands(
+        settings.a2dp_role,
+        settings.audio_input,
+        settings.a2dp_sink.as_deref(),
+        &app_state_with(|app_state| app_state.module.capabilities),
+    )
+}
+
+/// Parses an SPP request, ignoring those meant for other modules.
+pub async fn a2dp_source_handle(request: &str) -> CommandResult {
+    let request = match request.trim() {
+        "SOURCE I2S" => A2dpSourceRequest::EnableSource(AudioInput::I2s),
+        "SOURCE SPDIF" => A2dpSourceRequest::EnableSource(AudioInput::Spdif),
+        "SINK" => A2dpSourceRequest::EnableSink,
+        "SCAN" => A2dpSourceRequest::StartScan,
+        "SCAN STOP" => A2dpSourceRequest::StopScan,
+        request => match request.strip_prefix("CONNECT ") {
+            Some(mac) => A2dpSourceRequest::Connect(mac.trim().to_string()),
+            None => return Ok(()),
+        },
+    };
+
+    a2dp_source_request(request);
+
+    Ok(())
+}
+
+/// Reports headphones found while scanning to the SPP peer.
+pub async fn a2dp_source_scan_result(scan_result: &ScanResult) -> CommandResult {
+    spp_send(&format!(
+        "SCAN {} {} {}\n",
+        scan_result.mac, scan_result.rssi, scan_result.name
+    ))
+    .await
+}
+
+/// Applies requests from the user, storing the role and headphones chosen.
+#[embassy_executor::task]
+pub async fn a2dp_source_task() -> ! {
+    loop {
+        let request = a2dp_source_request_get().await;
+
+        if let Err(e) = a2dp_source_apply(request).await {
+            error!("{}", e);
+        }
+    }
+}
+
+async fn a2dp_source_apply(request: A2dpSourceRequest) -> CommandResult {
+    let mut settings = settings_load().await;
+
+    let commands = match request {
+        A2dpSourceRequest::EnableSource(audio_input) => {
+            settings.a2dp_role = A2dpRole::Source;
+            settings.audio_input = audio_input;
+            settings_store(&settings).await;
+
+            a2dp_source_setup(&settings)
+        }
+        A2dpSourceRequest::EnableSink => {
+            settings.a2dp_role = A2dpRole::Sink;
+            settings_store(&settings).await;
+
+            a2dp_source_setup(&settings)
+        }
+        A2dpSourceRequest::StartScan => vec![command::Scan::new().start().as_bytes().to_vec()],
+        A2dpSourceRequest::StopScan => vec![command::Scan::new().stop().as_bytes().to_vec()],
+        A2dpSourceRequest::Connect(a2dp_sink) => {
+            let commands = vec![
+                command::Scan::new().stop().as_bytes().to_vec(),
+                command::A2dpConn::new().mac(&a2dp_sink).as_bytes().to_vec(),
+            ];
+
+            settings.a2dp_sink = Some(a2dp_sink);
+            settings_store(&settings).await;
+
+            commands
+        }
+    };
+
+    for command in commands {
+        command_send(CommandPriority::High, &command).await?;
+    }
+
+    Ok(())
+}
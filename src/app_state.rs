@@ -1,36 +1,457 @@
-use alloc::string::String;
-use core::time::Duration;
-use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
-use embassy_sync::channel::Channel;
+use alloc::string::{String, ToString};
+use embassy_time::Instant;
 
-static APP_STATE_CHANNEL: Channel<ThreadModeRawMutex, AppState, 1> = Channel::new();
+use crate::{
+    call_state::CallState,
+    feasycom_capabilities::Capabilities,
+    feasycom_health::Recovery,
+    feasycom_protocol::indication::{
+        A2dpCodec, A2dpStat, AvrcpStat, GattStat, HfpStat, Indication, SppStat, Ver,
+    },
+    feasycom_state::FeasycomState,
+    line_framer::RxError,
+    playback_clock::PlaybackClock,
+    store::{Store, StoreSelector},
+};
 
-pub async fn app_state_set(app_state: AppState) {
-    APP_STATE_CHANNEL.send(app_state).await;
+const APP_STATE_SUBSCRIBERS: usize = 4;
+
+static APP_STATE: Store<AppState, APP_STATE_SUBSCRIBERS> = Store::new(AppState::new());
+
+pub type AppStateSelector<U, F> = StoreSelector<'static, AppState, U, F, APP_STATE_SUBSCRIBERS>;
+
+pub fn app_state_update(f: impl FnOnce(&mut AppState) -> bool) {
+    APP_STATE.update(f);
+}
+
+pub fn app_state_with<R>(f: impl FnOnce(&AppState) -> R) -> R {
+    APP_STATE.with(f)
+}
+
+/// Subscribes to one part of the state, e.g. `app_state_select(|app_state|
+/// app_state.playback.clone())`, ignoring changes to everything else.
+pub fn app_state_select<U, F>(select: F) -> AppStateSelector<U, F>
+where
+    U: PartialEq + Clone,
+    F: Fn(&AppState) -> U,
+{
+    APP_STATE.select(select)
 }
 
-pub async fn app_state_get() -> AppState {
-    APP_STATE_CHANNEL.receive().await
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum ModuleError {
+    Transport(RxError),
+    Parse,
+    Rejected,
 }
 
 #[derive(Debug, Eq, PartialEq, Clone)]
-pub struct AppState {
-    pub song_title: Option<String>,
-    pub song_artist: Option<String>,
-    pub song_album: Option<String>,
+pub struct ModuleState {
+    pub state: FeasycomState,
+    pub version: Option<Ver>,
+    pub capabilities: Capabilities,
+    pub error: Option<ModuleError>,
+    /// Set while the module is being recovered after it stopped answering.
+    pub recovery: Option<Recovery>,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct DeviceState {
+    pub address: Option<String>,
+    pub name: Option<String>,
+    pub a2dp_stat: A2dpStat,
+    pub avrcp_stat: AvrcpStat,
+    pub hfp_stat: HfpStat,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct PlaybackState {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
 
-    pub playback_elapsed_time: Option<Duration>,
-    pub playback_total_time: Option<Duration>,
+    pub clock: PlaybackClock,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct AudioState {
+    pub volume: Option<u8>,
+    pub a2dp_codec: Option<A2dpCodec>,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct DataState {
+    pub spp_stat: SppStat,
+    pub spp_address: Option<String>,
+    pub gatt_stat: GattStat,
+    pub gatt_address: Option<String>,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct AppState {
+    pub module: ModuleState,
+    pub device: DeviceState,
+    pub playback: PlaybackState,
+    pub audio: AudioState,
+    pub call: CallState,
+    pub data: DataState,
 }
 
 impl Default for AppState {
     fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AppState {
+    pub const fn new() -> Self {
         Self {
-            song_title: Default::default(),
-            song_artist: Default::default(),
-            song_album: Default::default(),
-            playback_elapsed_time: Default::default(),
-            playback_total_time: Default::default(),
+            module: ModuleState {
+                state: FeasycomState::PoweredOff,
+                version: None,
+                capabilities: Capabilities::new(),
+                error: None,
+                recovery: None,
+            },
+            device: DeviceState {
+                address: None,
+                name: None,
+                a2dp_stat: A2dpStat::Unsupported,
+                avrcp_stat: AvrcpStat::Unsupported,
+                hfp_stat: HfpStat::Unsupported,
+            },
+            playback: PlaybackState::new(),
+            audio: AudioState {
+                volume: None,
+                a2dp_codec: None,
+            },
+            call: CallState::Idle,
+            data: DataState {
+                spp_stat: SppStat::Unsupported,
+                spp_address: None,
+                gatt_stat: GattStat::Unsupported,
+                gatt_address: None,
+            },
         }
     }
+
+    /// Forgets what the module reported about the phone, playback, audio and
+    /// data links, called once the module has been restarted as it reports
+    /// them again.
+    pub fn restarted(&mut self) -> bool {
+        let Self {
+            device,
+            playback,
+            audio,
+            call,
+            data,
+            ..
+        } = Self::new();
+
+        update(&mut self.device, device)
+            | update(&mut self.playback, playback)
+            | update(&mut self.audio, audio)
+            | update(&mut self.call, call)
+            | update(&mut self.data, data)
+    }
+
+    /// Whether nothing is connected to the module, which then only reports
+    /// what it is asked for.
+    pub fn link_idle(&self) -> bool {
+        matches!(
+            self.device.a2dp_stat,
+            A2dpStat::Unsupported | A2dpStat::Standby
+        ) && matches!(
+            self.device.hfp_stat,
+            HfpStat::Unsupported | HfpStat::Standby
+        ) && matches!(self.data.spp_stat, SppStat::Unsupported | SppStat::Standby)
+            && matches!(
+                self.data.gatt_stat,
+                GattStat::Unsupported | GattStat::Standby
+            )
+    }
+
+    /// Merges the indication into the state, returning whether anything
+    /// changed so unchanged states are not published. `now` anchors the
+    /// playback clock.
+    pub fn reduce(&mut self, indication: &Indication, now: Instant) -> bool {
+        let call = self.call.next(indication);
+        let call_changed = update(&mut self.call, call);
+
+        let changed = match indication {
+            Indication::Ok => update(&mut self.module.error, None),
+            Indication::Err => update(&mut self.module.error, Some(ModuleError::Rejected)),
+            Indication::Ver(version) => {
+                update(&mut self.module.version, Some(version.clone()))
+                    | update(&mut self.module.capabilities, Capabilities::of(version))
+            }
+
+            Indication::A2dpDev(device) => {
+                let (address, name) = split_device(&device.0);
+
+                update(&mut self.device.address, address) | update(&mut self.device.name, name)
+            }
+            Indication::A2dpStat(a2dp_stat) => {
+                let mut changed = update(&mut self.device.a2dp_stat, *a2dp_stat);
+
+                if !a2dp_connected(*a2dp_stat) {
+                    changed |= update(&mut self.audio.a2dp_codec, None)
+                        | update(&mut self.playback, PlaybackState::new())
+                        | self.device.disconnected();
+                }
+
+                changed
+            }
+            Indication::AvrcpStat(avrcp_stat) => {
+                let mut changed = update(&mut self.device.avrcp_stat, *avrcp_stat);
+
+                if *avrcp_stat != AvrcpStat::Connected {
+                    changed |= update(&mut self.playback, PlaybackState::new());
+                }
+
+                changed
+            }
+            Indication::HfpStat(hfp_stat) => {
+                let mut changed = update(&mut self.device.hfp_stat, *hfp_stat);
+
+                if !hfp_connected(*hfp_stat) {
+                    changed |= self.device.disconnected();
+                }
+
+                changed
+            }
+
+            Indication::TrackInfo(track_info) => {
+                update(&mut self.playback.title, non_empty(&track_info.title))
+                    | update(&mut self.playback.artist, non_empty(&track_info.artist))
+                    | update(&mut self.playback.album, non_empty(&track_info.album))
+            }
+            Indication::TrackStat(track_stat) => {
+                let mut clock = self.playback.clock;
+                clock.anchor(
+                    track_stat.elapsed_time,
+                    track_stat.total_time,
+                    track_stat.play_stat,
+                    now,
+                );
+
+                reanchor(&mut self.playback.clock, clock)
+            }
+            Indication::PlayStat(play_stat) => {
+                let mut clock = self.playback.clock;
+                clock.set_play_stat(*play_stat, now);
+
+                reanchor(&mut self.playback.clock, clock)
+            }
+
+            Indication::SpkVol(level) => update(&mut self.audio.volume, Some(level.0)),
+            Indication::A2dpCodec(a2dp_codec) => {
+                update(&mut self.audio.a2dp_codec, Some(*a2dp_codec))
+            }
+
+            Indication::SppStat(spp_stat) => {
+                let mut changed = update(&mut self.data.spp_stat, *spp_stat);
+
+                if *spp_stat != SppStat::Connected {
+                    changed |= update(&mut self.data.spp_address, None);
+                }
+
+                changed
+            }
+            Indication::SppDev(device) => {
+                update(&mut self.data.spp_address, split_device(&device.0).0)
+            }
+            Indication::GattStat(gatt_stat) => {
+                let mut changed = update(&mut self.data.gatt_stat, *gatt_stat);
+
+                if *gatt_stat != GattStat::Connected {
+                    changed |= update(&mut self.data.gatt_address, None);
+                }
+
+                changed
+            }
+            Indication::GattDev(device) => {
+                update(&mut self.data.gatt_address, split_device(&device.0).0)
+            }
+
+            _ => false,
+        };
+
+        call_changed | changed
+    }
+}
+
+impl DeviceState {
+    /// Forgets the device once neither A2DP nor HFP is connected to it,
+    /// returning whether anything changed.
+    fn disconnected(&mut self) -> bool {
+        if a2dp_connected(self.a2dp_stat) || hfp_connected(self.hfp_stat) {
+            return false;
+        }
+
+        update(&mut self.address, None) | update(&mut self.name, None)
+    }
+}
+
+impl Default for PlaybackState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PlaybackState {
+    pub const fn new() -> Self {
+        Self {
+            title: None,
+            artist: None,
+            album: None,
+            clock: PlaybackClock::new(),
+        }
+    }
+}
+
+pub fn update<T: PartialEq>(field: &mut T, value: T) -> bool {
+    if *field == value {
+        return false;
+    }
+
+    *field = value;
+    true
+}
+
+/// Keeps the new anchor even when the clock reports the same position, so
+/// the estimate runs from the latest report.
+fn reanchor(clock: &mut PlaybackClock, value: PlaybackClock) -> bool {
+    let changed = *clock != value;
+    *clock = value;
+    changed
+}
+
+fn a2dp_connected(a2dp_stat: A2dpStat) -> bool {
+    matches!(a2dp_stat, A2dpStat::Connected | A2dpStat::Streaming)
+}
+
+fn hfp_connected(hfp_stat: HfpStat) -> bool {
+    !matches!(
+        hfp_stat,
+        HfpStat::Unsupported | HfpStat::Standby | HfpStat::Connecting
+    )
+}
+
+fn non_empty(value: &str) -> Option<String> {
+    match value.trim() {
+        "" => None,
+        value => Some(value.to_string()),
+    }
+}
+
+/// Device indications carry the address, optionally followed by the name.
+fn split_device(value: &str) -> (Option<String>, Option<String>) {
+    match value.split_once(',') {
+        Some((address, name)) => (non_empty(address), non_empty(name)),
+        None => (non_empty(value), None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::feasycom_protocol::indication::{A2dpDev, PlayStat, TrackStat};
+    use core::time::Duration;
+
+    fn reduce(app_state: &mut AppState, indications: &[Indication]) {
+        for indication in indications {
+            app_state.reduce(indication, Instant::from_secs(0));
+        }
+    }
+
+    #[test]
+    fn module_error_clears_on_next_ok() {
+        let mut app_state = AppState::new();
+
+        reduce(&mut app_state, &[Indication::Err]);
+        assert_eq!(app_state.module.error, Some(ModuleError::Rejected));
+
+        assert!(app_state.reduce(&Indication::Ok, Instant::from_secs(0)));
+        assert_eq!(app_state.module.error, None);
+    }
+
+    #[test]
+    fn device_is_kept_until_both_profiles_are_down() {
+        let mut app_state = AppState::new();
+
+        reduce(
+            &mut app_state,
+            &[
+                Indication::HfpStat(HfpStat::Connected),
+                Indication::A2dpStat(A2dpStat::Connected),
+                Indication::A2dpDev(A2dpDev("0C:AB:12:34:56:78,Pixel 8".to_string())),
+                Indication::A2dpStat(A2dpStat::Standby),
+            ],
+        );
+        assert_eq!(
+            app_state.device.address.as_deref(),
+            Some("0C:AB:12:34:56:78")
+        );
+        assert_eq!(app_state.device.name.as_deref(), Some("Pixel 8"));
+
+        reduce(&mut app_state, &[Indication::HfpStat(HfpStat::Standby)]);
+        assert_eq!(app_state.device.address, None);
+        assert_eq!(app_state.device.name, None);
+    }
+
+    #[test]
+    fn device_is_kept_while_streaming_after_hfp_drops() {
+        let mut app_state = AppState::new();
+
+        reduce(
+            &mut app_state,
+            &[
+                Indication::A2dpStat(A2dpStat::Streaming),
+                Indication::A2dpDev(A2dpDev("0C:AB:12:34:56:78".to_string())),
+                Indication::HfpStat(HfpStat::Standby),
+            ],
+        );
+        assert_eq!(
+            app_state.device.address.as_deref(),
+            Some("0C:AB:12:34:56:78")
+        );
+    }
+
+    #[test]
+    fn repeated_track_status_is_not_a_change() {
+        let mut app_state = AppState::new();
+        let paused = Indication::TrackStat(TrackStat {
+            play_stat: PlayStat::Paused,
+            elapsed_time: Duration::from_secs(42),
+            total_time: Duration::from_secs(180),
+        });
+
+        assert!(app_state.reduce(&paused, Instant::from_secs(10)));
+        assert!(!app_state.reduce(&paused, Instant::from_secs(15)));
+        assert!(!app_state.reduce(
+            &Indication::PlayStat(PlayStat::Paused),
+            Instant::from_secs(20)
+        ));
+        assert!(app_state.reduce(
+            &Indication::PlayStat(PlayStat::Playing),
+            Instant::from_secs(25)
+        ));
+    }
+
+    #[test]
+    fn restart_forgets_the_phone() {
+        let mut app_state = AppState::new();
+        let now = Instant::from_secs(0);
+
+        app_state.reduce(&Indication::A2dpStat(A2dpStat::Streaming), now);
+        app_state.reduce(&Indication::HfpStat(HfpStat::ActiveCall), now);
+        app_state.reduce(&Indication::Err, now);
+
+        assert!(app_state.restarted());
+        assert_eq!(app_state.device.a2dp_stat, A2dpStat::Unsupported);
+        assert_eq!(app_state.call, CallState::Idle);
+        assert_eq!(app_state.module.error, Some(ModuleError::Rejected));
+        assert!(!app_state.restarted());
+    }
 }
@@ -0,0 +1,207 @@
+use alloc::string::String;
+use defmt::Format;
+
+use crate::{
+    feasycom_protocol::indication::{HfpAudio, HfpStat, Indication},
+    phonebook::contact_index::contact_index_lookup,
+};
+
+#[derive(Debug, Eq, PartialEq, Clone, Format)]
+pub struct Call {
+    pub number: Option<String>,
+    pub name: Option<String>,
+    pub audio: HfpAudio,
+}
+
+impl Default for Call {
+    fn default() -> Self {
+        Self {
+            number: Default::default(),
+            name: Default::default(),
+            audio: HfpAudio::Phone,
+        }
+    }
+}
+
+#[derive(Debug, Default, Eq, PartialEq, Clone, Format)]
+pub enum CallState {
+    #[default]
+    Idle,
+    Incoming(Call),
+    Outgoing(Call),
+    Active(Call),
+    Held(Call),
+}
+
+impl CallState {
+    pub fn call(&self) -> Option<&Call> {
+        match self {
+            Self::Idle => None,
+            Self::Incoming(call) | Self::Outgoing(call) | Self::Active(call) | Self::Held(call) => {
+                Some(call)
+            }
+        }
+    }
+
+    fn with_call(&self, call: Call) -> Self {
+        match self {
+            Self::Idle => Self::Incoming(call),
+            Self::Incoming(_) => Self::Incoming(call),
+            Self::Outgoing(_) => Self::Outgoing(call),
+            Self::Active(_) => Self::Active(call),
+            Self::Held(_) => Self::Held(call),
+        }
+    }
+
+    pub fn next(&self, indication: &Indication) -> Self {
+        let call = self.call().cloned().unwrap_or_default();
+
+        match indication {
+            Indication::HfpStat(HfpStat::OutgoingCall) => Self::Outgoing(call),
+            Indication::HfpStat(HfpStat::IncomingCall) => Self::Incoming(call),
+            Indication::HfpStat(HfpStat::ActiveCall) => Self::Active(call),
+            Indication::HfpStat(HfpStat::HeldCall) => Self::Held(call),
+            Indication::HfpStat(_) => Self::Idle,
+
+            Indication::HfpRing(_) => self.with_call(call),
+
+            // The module reports caller ID for both directions, but before
+            // +HFPSTAT it can only be a ringing phone.
+            Indication::HfpCid(number) => self.with_call(Call {
+                number: Some(number.0.clone()),
+                name: contact_index_lookup(&number.0),
+                ..call
+            }),
+
+            Indication::HfpAudio(audio) => match self {
+                Self::Idle => Self::Idle,
+                _ => self.with_call(Call {
+                    audio: *audio,
+                    ..call
+                }),
+            },
+
+            _ => self.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        feasycom_protocol::indication::{HfpCid, HfpRing, SpkVol},
+        phonebook::{
+            contact_index::contact_index_insert,
+            vcard::{Contact, NumberKind, PhoneNumber},
+        },
+        test_lock,
+    };
+    use alloc::{string::ToString, vec};
+
+    fn call(number: Option<&str>, name: Option<&str>, audio: HfpAudio) -> Call {
+        Call {
+            number: number.map(str::to_string),
+            name: name.map(str::to_string),
+            audio,
+        }
+    }
+
+    fn run(steps: &[(Indication, CallState)]) {
+        let mut state = CallState::Idle;
+
+        for (indication, expected) in steps {
+            state = state.next(indication);
+            assert_eq!(&state, expected, "after {:?}", indication);
+        }
+    }
+
+    #[test]
+    fn incoming_call_answered_held_and_ended() {
+        let _lock = test_lock();
+
+        contact_index_insert(&Contact {
+            name: "Grace Hopper".to_string(),
+            numbers: vec![PhoneNumber {
+                kind: NumberKind::Cell,
+                number: "0498 765 432".to_string(),
+            }],
+        });
+
+        let ringing = call(Some("+61498765432"), Some("Grace Hopper"), HfpAudio::Phone);
+        let answered = call(Some("+61498765432"), Some("Grace Hopper"), HfpAudio::Device);
+
+        run(&[
+            (
+                Indication::HfpRing(HfpRing),
+                CallState::Incoming(Call::default()),
+            ),
+            (
+                Indication::HfpCid(HfpCid("+61498765432".to_string())),
+                CallState::Incoming(ringing.clone()),
+            ),
+            (
+                Indication::HfpStat(HfpStat::IncomingCall),
+                CallState::Incoming(ringing.clone()),
+            ),
+            (
+                Indication::HfpStat(HfpStat::ActiveCall),
+                CallState::Active(ringing),
+            ),
+            (
+                Indication::HfpAudio(HfpAudio::Device),
+                CallState::Active(answered.clone()),
+            ),
+            (
+                Indication::SpkVol(SpkVol(9)),
+                CallState::Active(answered.clone()),
+            ),
+            (
+                Indication::HfpStat(HfpStat::HeldCall),
+                CallState::Held(answered.clone()),
+            ),
+            (
+                Indication::HfpStat(HfpStat::ActiveCall),
+                CallState::Active(answered),
+            ),
+            (Indication::HfpStat(HfpStat::Connected), CallState::Idle),
+        ]);
+    }
+
+    #[test]
+    fn outgoing_call_to_unknown_number() {
+        let dialled = call(Some("1800 555 0199"), None, HfpAudio::Phone);
+
+        run(&[
+            (
+                Indication::HfpStat(HfpStat::OutgoingCall),
+                CallState::Outgoing(Call::default()),
+            ),
+            (
+                Indication::HfpCid(HfpCid("1800 555 0199".to_string())),
+                CallState::Outgoing(dialled.clone()),
+            ),
+            (
+                Indication::HfpStat(HfpStat::ActiveCall),
+                CallState::Active(dialled),
+            ),
+            (Indication::HfpStat(HfpStat::Standby), CallState::Idle),
+        ]);
+    }
+
+    #[test]
+    fn audio_without_call_is_ignored() {
+        run(&[
+            (Indication::HfpAudio(HfpAudio::Device), CallState::Idle),
+            (Indication::HfpStat(HfpStat::Connected), CallState::Idle),
+        ]);
+    }
+
+    #[test]
+    fn caller_id_before_status_is_a_ringing_phone() {
+        run(&[(
+            Indication::HfpCid(HfpCid("5550100".to_string())),
+            CallState::Incoming(call(Some("5550100"), None, HfpAudio::Phone)),
+        )]);
+    }
+}
@@ -0,0 +1,534 @@
+use alloc::{collections::VecDeque, sync::Arc, vec::Vec};
+use defmt::Format;
+use embassy_futures::select::{select3, Either3};
+use embassy_sync::{channel::Channel, signal::Signal};
+use embassy_time::{Duration, Instant, Timer};
+
+use crate::{feasycom_protocol::indication::Indication, usart, TaskRawMutex};
+
+/// Commands each priority can hold before senders wait for room.
+const QUEUE_CAPACITY: usize = 4;
+
+/// Commands sent from higher priorities while a lower priority one waits,
+/// after which the waiting command goes first so it is never starved.
+const STARVATION_LIMIT: u8 = 4;
+
+/// Time for the module to answer most commands, from when the command is
+/// taken from the queue.
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// `AT+PBDOWN` only answers once every contact has been sent.
+const PB_DOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// `AT+SCAN` answers once the inquiry set up by `AT+INQCFG` is over.
+const SCAN_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// `AT+REBOOT` and `AT+RESTORE` answer after the module restarts.
+const RESTART_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Time after a timeout during which nothing is sent, so an answer arriving
+/// late is dropped rather than taken as the next command's.
+const LATE_ANSWER_TIME: Duration = Duration::from_millis(500);
+
+/// Times a query is sent again after it times out, asking twice only costs
+/// a second answer.
+const QUERY_RETRIES: u8 = 2;
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Format)]
+pub enum CommandPriority {
+    /// Commands the user is waiting on, such as `AT+PLAYPAUSE`.
+    High,
+    Normal,
+    /// Bulk transfers such as `AT+PBDOWN`, which may wait behind anything.
+    Low,
+}
+
+const PRIORITIES: [CommandPriority; 3] = [
+    CommandPriority::High,
+    CommandPriority::Normal,
+    CommandPriority::Low,
+];
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Format)]
+pub enum AtError {
+    /// Neither `OK` nor `ERROR` arrived in time.
+    Timeout,
+    /// The module answered `ERROR`.
+    Rejected,
+    /// The fitted module does not support the command, it was not written.
+    Unsupported,
+    /// The module is not in a state to take the command, it was not written.
+    NotReady,
+    Transport(usart::Error),
+}
+
+impl From<usart::Error> for AtError {
+    fn from(e: usart::Error) -> Self {
+        Self::Transport(e)
+    }
+}
+
+pub type CommandResult = Result<(), AtError>;
+
+/// How long to wait for a command's answer and how often to send it again
+/// after a timeout or transport error, which the arbiter does before the
+/// sender hears back. Only commands that can safely be sent
+/// twice, such as queries or setting an absolute value, should be retried.
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Format)]
+pub struct CommandPolicy {
+    timeout: Duration,
+    retries: u8,
+}
+
+impl CommandPolicy {
+    /// The default for a command, only queries are retried.
+    pub fn of(command: &[u8]) -> Self {
+        let timeout = match command_name(command) {
+            b"AT+PBDOWN" => PB_DOWN_TIMEOUT,
+            b"AT+SCAN" => SCAN_TIMEOUT,
+            b"AT+REBOOT" | b"AT+RESTORE" => RESTART_TIMEOUT,
+            _ => COMMAND_TIMEOUT,
+        };
+
+        Self {
+            timeout,
+            retries: if is_query(command) { QUERY_RETRIES } else { 0 },
+        }
+    }
+
+    pub fn timeout(self, timeout: Duration) -> Self {
+        Self { timeout, ..self }
+    }
+
+    pub fn retries(self, retries: u8) -> Self {
+        Self { retries, ..self }
+    }
+}
+
+/// The command without its parameters, e.g. `AT+PBDOWN`.
+pub fn command_name(command: &[u8]) -> &[u8] {
+    command
+        .split(|c| *c == b'=' || *c == b'\r')
+        .next()
+        .unwrap_or(command)
+}
+
+/// Commands that only report state, e.g. `AT+VER` or `AT+TRACKSTAT`. The
+/// same names take a parameter to change the state, e.g. `AT+SPKVOL=+`, so
+/// only the bare command is a query.
+fn is_query(command: &[u8]) -> bool {
+    let name = command_name(command);
+
+    if name.len() != command.trim_ascii_end().len() {
+        return false;
+    }
+
+    matches!(
+        name,
+        b"AT+VER"
+            | b"AT+ADDR"
+            | b"AT+LEADDR"
+            | b"AT+NAME"
+            | b"AT+LENAME"
+            | b"AT+A2DPDEV"
+            | b"AT+A2DPDEC"
+            | b"AT+SPKVOL"
+            | b"AT+TRACKINFO"
+    ) || name.ends_with(b"STAT")
+}
+
+type CommandCompletion = Arc<Signal<TaskRawMutex, CommandResult>>;
+
+pub struct CommandRequest {
+    priority: CommandPriority,
+    command: Vec<u8>,
+    timeout: Duration,
+    retries: u8,
+    completion: Option<CommandCompletion>,
+}
+
+impl CommandRequest {
+    pub fn priority(&self) -> CommandPriority {
+        self.priority
+    }
+
+    pub fn command(&self) -> &[u8] {
+        &self.command
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Wakes the sender, if it is waiting on the command.
+    pub fn complete(self, result: CommandResult) {
+        if let Some(completion) = self.completion {
+            completion.signal(result);
+        }
+    }
+}
+
+static COMMAND_QUEUES: [Channel<TaskRawMutex, CommandRequest, QUEUE_CAPACITY>; 3] =
+    [Channel::new(), Channel::new(), Channel::new()];
+
+fn command_queue(
+    priority: CommandPriority,
+) -> &'static Channel<TaskRawMutex, CommandRequest, QUEUE_CAPACITY> {
+    &COMMAND_QUEUES[priority as usize]
+}
+
+/// Queues a command for the module and waits for its answer, from any task.
+/// The command is the output of a command builder's `as_bytes`.
+pub async fn command_send(priority: CommandPriority, command: &[u8]) -> CommandResult {
+    command_send_with(priority, command, CommandPolicy::of(command)).await
+}
+
+pub async fn command_send_with(
+    priority: CommandPriority,
+    command: &[u8],
+    policy: CommandPolicy,
+) -> CommandResult {
+    let completion = Arc::new(Signal::new());
+
+    command_queue(priority)
+        .send(CommandRequest {
+            priority,
+            command: command.to_vec(),
+            timeout: policy.timeout,
+            retries: policy.retries,
+            completion: Some(completion.clone()),
+        })
+        .await;
+
+    completion.wait().await
+}
+
+struct InFlight {
+    request: CommandRequest,
+    /// Answers due to commands written before this one.
+    answers_ahead: u8,
+    deadline: Instant,
+}
+
+/// Picks the next queued command, highest priority first unless a lower
+/// priority command has been passed over too often, and matches the module's
+/// answer to it. Only one queued command is in flight at a time.
+pub struct CommandArbiter {
+    passed_over: [u8; 3],
+    in_flight: Option<InFlight>,
+    /// End of the wait after a timeout for its answer to turn up late.
+    late_until: Option<Instant>,
+    /// Commands from the task running the arbiter, which cannot wait for
+    /// room in the queues or for its own answers, and commands being retried.
+    posted: VecDeque<CommandRequest>,
+}
+
+impl Default for CommandArbiter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CommandArbiter {
+    pub const fn new() -> Self {
+        Self {
+            passed_over: [0; 3],
+            in_flight: None,
+            late_until: None,
+            posted: VecDeque::new(),
+        }
+    }
+
+    /// Queues a command without waiting for room or for its answer, for the
+    /// task running the arbiter. Other tasks use `command_send`.
+    pub fn post(&mut self, priority: CommandPriority, command: &[u8]) {
+        let policy = CommandPolicy::of(command);
+
+        self.posted.push_back(CommandRequest {
+            priority,
+            command: command.to_vec(),
+            timeout: policy.timeout,
+            retries: policy.retries,
+            completion: None,
+        });
+    }
+
+    /// Waits for the next command to send, never resolving while a command
+    /// is in flight or a timed out one may still be answered.
+    pub async fn next(&mut self) -> CommandRequest {
+        if self.in_flight.is_some() {
+            return core::future::pending().await;
+        }
+
+        if let Some(late_until) = self.late_until {
+            Timer::at(late_until).await;
+            self.late_until = None;
+        }
+
+        if let Some(request) = self.try_next() {
+            return request;
+        }
+
+        // Every queue is empty so whichever receives first is the only
+        // command waiting, `select3` polls in priority order for a tie.
+        let request = match select3(
+            command_queue(CommandPriority::High).receive(),
+            command_queue(CommandPriority::Normal).receive(),
+            command_queue(CommandPriority::Low).receive(),
+        )
+        .await
+        {
+            Either3::First(request) | Either3::Second(request) | Either3::Third(request) => request,
+        };
+
+        self.served(request.priority);
+        request
+    }
+
+    /// Called once a command has been written, `answers_ahead` being the
+    /// answers due to other commands written before it.
+    pub fn sent(&mut self, request: CommandRequest, answers_ahead: u8, deadline: Instant) {
+        self.in_flight = Some(InFlight {
+            request,
+            answers_ahead,
+            deadline,
+        });
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        self.in_flight.as_ref().map(|in_flight| in_flight.deadline)
+    }
+
+    /// Called for every `OK` or `ERROR` received, returns the command
+    /// answered if it is the one in flight. With nothing in flight the answer
+    /// is a late one and is dropped.
+    pub fn answered(&mut self, indication: &Indication) -> Option<Vec<u8>> {
+        let in_flight = self.in_flight.as_mut()?;
+
+        if in_flight.answers_ahead > 0 {
+            in_flight.answers_ahead -= 1;
+            return None;
+        }
+
+        let result = match indication {
+            Indication::Err => Err(AtError::Rejected),
+            _ => Ok(()),
+        };
+
+        let request = self.in_flight.take()?.request;
+        let command = request.command.clone();
+        request.complete(result);
+
+        Some(command)
+    }
+
+    /// Retries or fails the command in flight, called once `deadline` has
+    /// passed. Returns the answers still due, its own included, which are
+    /// all taken as lost. Nothing is sent for `LATE_ANSWER_TIME`, so its own
+    /// answer arriving late finds nothing in flight.
+    pub fn timeout(&mut self) -> u8 {
+        let Some(in_flight) = self.in_flight.take() else {
+            return 0;
+        };
+
+        self.late_until = Some(in_flight.deadline + LATE_ANSWER_TIME);
+        self.failed(in_flight.request, AtError::Timeout);
+        in_flight.answers_ahead + 1
+    }
+
+    /// Sends the command again ahead of anything queued if it has retries
+    /// left, otherwise completes it with `error`.
+    pub fn failed(&mut self, mut request: CommandRequest, error: AtError) {
+        if request.retries == 0 {
+            request.complete(Err(error));
+            return;
+        }
+
+        request.retries -= 1;
+        self.posted.push_front(request);
+    }
+
+    /// Fails the command in flight and drops posted commands, called when
+    /// the module is restarted. The task posts what it still needs again
+    /// once the module is up.
+    pub fn reset(&mut self) {
+        if let Some(in_flight) = self.in_flight.take() {
+            in_flight.request.complete(Err(AtError::Timeout));
+        }
+
+        for request in self.posted.drain(..) {
+            request.complete(Err(AtError::Timeout));
+        }
+    }
+
+    fn try_next(&mut self) -> Option<CommandRequest> {
+        let starved = PRIORITIES.into_iter().rev().find(|priority| {
+            self.passed_over[*priority as usize] >= STARVATION_LIMIT && self.waiting(*priority)
+        });
+
+        let request = starved
+            .into_iter()
+            .chain(PRIORITIES)
+            .find_map(|priority| self.take(priority))?;
+
+        self.served(request.priority);
+        Some(request)
+    }
+
+    fn waiting(&self, priority: CommandPriority) -> bool {
+        !command_queue(priority).is_empty()
+            || self
+                .posted
+                .iter()
+                .any(|request| request.priority == priority)
+    }
+
+    /// Posted commands go ahead of queued ones of the same priority, so a
+    /// retry is sent before anything else.
+    fn take(&mut self, priority: CommandPriority) -> Option<CommandRequest> {
+        match self
+            .posted
+            .iter()
+            .position(|request| request.priority == priority)
+        {
+            Some(index) => self.posted.remove(index),
+            None => command_queue(priority).try_receive().ok(),
+        }
+    }
+
+    fn served(&mut self, priority: CommandPriority) {
+        self.passed_over[priority as usize] = 0;
+
+        for lower in PRIORITIES.into_iter().skip(priority as usize + 1) {
+            if self.waiting(lower) {
+                self.passed_over[lower as usize] =
+                    self.passed_over[lower as usize].saturating_add(1);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::feasycom_protocol::command;
+
+    fn request(command: &[u8]) -> (CommandRequest, CommandCompletion) {
+        let completion = Arc::new(Signal::new());
+        let request = CommandRequest {
+            priority: CommandPriority::Normal,
+            command: command.to_vec(),
+            timeout: COMMAND_TIMEOUT,
+            retries: 0,
+            completion: Some(completion.clone()),
+        };
+
+        (request, completion)
+    }
+
+    #[test]
+    fn only_queries_are_retried() {
+        assert_eq!(CommandPolicy::of(command::Ver::new().as_bytes()).retries, 2);
+        assert_eq!(
+            CommandPolicy::of(command::Stat::new().as_bytes()).retries,
+            2
+        );
+        assert_eq!(
+            CommandPolicy::of(command::TrackStat::new().as_bytes()).retries,
+            2
+        );
+        assert_eq!(
+            CommandPolicy::of(command::SpkVol::new().as_bytes()).retries,
+            2
+        );
+        assert_eq!(
+            CommandPolicy::of(command::SpkVol::new().increase().as_bytes()).retries,
+            0
+        );
+        assert_eq!(
+            CommandPolicy::of(command::Reboot::new().as_bytes()).retries,
+            0
+        );
+    }
+
+    #[test]
+    fn answers_ahead_are_skipped() {
+        let mut command_arbiter = CommandArbiter::new();
+        let (request, completion) = request(command::Ver::new().as_bytes());
+
+        command_arbiter.sent(request, 1, Instant::from_secs(1));
+        assert_eq!(command_arbiter.answered(&Indication::Err), None);
+        assert!(!completion.signaled());
+
+        assert_eq!(
+            command_arbiter.answered(&Indication::Ok).as_deref(),
+            Some(command::Ver::new().as_bytes())
+        );
+        assert_eq!(completion.try_take(), Some(Ok(())));
+        assert_eq!(command_arbiter.deadline(), None);
+    }
+
+    #[test]
+    fn timeout_reports_the_answers_still_ahead() {
+        let mut command_arbiter = CommandArbiter::new();
+        let (request, completion) = request(command::Ver::new().as_bytes());
+
+        command_arbiter.sent(request, 2, Instant::from_secs(1));
+        command_arbiter.answered(&Indication::Ok);
+
+        assert_eq!(command_arbiter.timeout(), 2);
+        assert_eq!(completion.try_take(), Some(Err(AtError::Timeout)));
+        assert_eq!(command_arbiter.timeout(), 0);
+    }
+
+    #[test]
+    fn timed_out_queries_are_sent_again_first() {
+        let mut command_arbiter = CommandArbiter::new();
+
+        command_arbiter.post(CommandPriority::Low, command::Stat::new().as_bytes());
+        command_arbiter.post(CommandPriority::High, command::Ver::new().as_bytes());
+
+        for _ in 0..=QUERY_RETRIES {
+            let request = command_arbiter.try_next().unwrap();
+            assert_eq!(request.command(), command::Ver::new().as_bytes());
+
+            command_arbiter.sent(request, 0, Instant::from_secs(1));
+            command_arbiter.timeout();
+        }
+
+        let request = command_arbiter.try_next().unwrap();
+        assert_eq!(request.command(), command::Stat::new().as_bytes());
+        assert!(command_arbiter.try_next().is_none());
+    }
+
+    #[test]
+    fn lost_answers_do_not_hold_up_the_next_command() {
+        let mut command_arbiter = CommandArbiter::new();
+        let (first, first_completion) = request(command::Stat::new().as_bytes());
+        let (second, second_completion) = request(command::Ver::new().as_bytes());
+
+        // Stands in for the transmitter's count of answers due.
+        let mut unanswered: u8 = 1;
+        command_arbiter.sent(first, unanswered - 1, Instant::from_secs(1));
+        unanswered -= command_arbiter.timeout();
+        assert_eq!(first_completion.try_take(), Some(Err(AtError::Timeout)));
+
+        unanswered += 1;
+        command_arbiter.sent(second, unanswered - 1, Instant::from_secs(3));
+        assert_eq!(
+            command_arbiter.answered(&Indication::Ok).as_deref(),
+            Some(command::Ver::new().as_bytes())
+        );
+        assert_eq!(second_completion.try_take(), Some(Ok(())));
+    }
+
+    #[test]
+    fn late_answers_are_dropped() {
+        let mut command_arbiter = CommandArbiter::new();
+        let (request, _completion) = request(command::Stat::new().as_bytes());
+
+        command_arbiter.sent(request, 0, Instant::from_secs(1));
+        assert_eq!(command_arbiter.timeout(), 1);
+        assert_eq!(command_arbiter.answered(&Indication::Ok), None);
+    }
+}
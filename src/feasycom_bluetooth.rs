@@ -1,28 +1,161 @@
 use alloc::vec;
 use alloc::{boxed::Box, vec::Vec};
-use defmt::{error, info};
+use core::str;
+use defmt::{error, warn};
+use embassy_futures::select::{select, Either};
+#[cfg(feature = "single-usart")]
+use embassy_stm32::usart::Uart;
 use embassy_stm32::{
-    bind_interrupts, peripherals,
+    bind_interrupts,
+    gpio::{Level, Output, Speed},
+    peripherals,
     usart::{self, Config, ConfigError, RingBufferedUartRx, UartRx, UartTx},
 };
+use embassy_time::{Duration, Instant, Timer};
+
+#[cfg(feature = "uart-capture")]
+use crate::uart_capture::{uart_capture_record, Direction};
+use crate::{
+    app_state::app_state_with,
+    command_queue::{AtError, CommandResult},
+    feasycom_capabilities::CommandGate,
+    feasycom_protocol::command,
+    line_framer::{LineFramer, RxError, LINE_CAPACITY},
+};
 
 bind_interrupts!(struct Irqs {
     USART1 => usart::InterruptHandler<peripherals::USART1>;
 });
 
+/// Boards wired like the original use TX on USART6 and RX on USART1, with the
+/// `single-usart` feature both directions share USART1 instead.
+#[cfg(not(feature = "single-usart"))]
+type TxUsart = peripherals::USART6;
+#[cfg(not(feature = "single-usart"))]
+type TxDma = peripherals::DMA2_CH6;
+#[cfg(feature = "single-usart")]
+type TxUsart = peripherals::USART1;
+#[cfg(feature = "single-usart")]
+type TxDma = peripherals::DMA2_CH7;
+
 const RING_BUFFER_SIZE: usize = 256;
 
+/// The module only treats `+++` as an escape from throughput mode when the
+/// line has been idle for the guard time on both sides of it.
+const ESCAPE_SEQUENCE: &[u8] = b"+++";
+const ESCAPE_GUARD_TIME: Duration = Duration::from_millis(1000);
+
+/// How often `AT` is sent while waiting for the module to boot.
+const BOOT_PROBE_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Time without a line after the module answers, for the answers to earlier
+/// probes to arrive before anything else is sent.
+const BOOT_QUIET_TIME: Duration = Duration::from_millis(100);
+
+/// The line the module prints once it has booted.
+const BOOT_BANNER: &[u8] = b"+READY";
+
+/// The module's reset input is active low and needs holding for at least
+/// 10 ms to be recognised.
+const RESET_PULSE: Duration = Duration::from_millis(20);
+
+/// Time the module is left switched off when power cycled, for its supply to
+/// discharge.
+const POWER_OFF_TIME: Duration = Duration::from_millis(200);
+
+/// Time for the module's supply to settle after it is switched on, reset is
+/// held for this long so the module starts from a known state.
+const POWER_SETTLE_TIME: Duration = Duration::from_millis(50);
+
 pub struct FeasycomBluetoothTx<'a> {
-    tx: UartTx<'a, peripherals::USART6, peripherals::DMA2_CH6>,
+    tx: UartTx<'a, TxUsart, TxDma>,
+    /// Commands written that the module has not answered with `OK` or
+    /// `ERROR` yet, it answers them in order.
+    unanswered: u8,
 }
 
 pub struct FeasycomBluetoothRx<'a> {
     rx: RingBufferedUartRx<'a, peripherals::USART1>,
+    flow_control: bool,
     buf: [u8; RING_BUFFER_SIZE / 2],
-    msg: Vec<u8>,
+    buf_pos: usize,
+    buf_len: usize,
+    framer: LineFramer<LINE_CAPACITY>,
+}
+
+/// Board level control of the module, its active low reset input and the
+/// enable input of the load switch supplying it.
+pub struct FeasycomBluetoothControl<'a> {
+    reset: Output<'a, peripherals::PB0>,
+    power: Output<'a, peripherals::PB1>,
+}
+
+/// Opens both directions on USART1, TX on PB6 and RX on PB7. Flow control
+/// uses RTS on PA12 and CTS on PA11 when `rts_cts_pins` is given.
+#[cfg(feature = "single-usart")]
+pub fn feasycom_bluetooth_new<'a>(
+    peri: peripherals::USART1,
+    tx_pin: peripherals::PB6,
+    rx_pin: peripherals::PB7,
+    rts_cts_pins: Option<(peripherals::PA12, peripherals::PA11)>,
+    tx_dma: peripherals::DMA2_CH7,
+    rx_dma: peripherals::DMA2_CH2,
+) -> Result<(FeasycomBluetoothTx<'a>, FeasycomBluetoothRx<'a>), ConfigError> {
+    let flow_control = rts_cts_pins.is_some();
+
+    let uart = match rts_cts_pins {
+        Some((rts_pin, cts_pin)) => Uart::new_with_rtscts(
+            peri,
+            rx_pin,
+            tx_pin,
+            Irqs,
+            rts_pin,
+            cts_pin,
+            tx_dma,
+            rx_dma,
+            Config::default(),
+        )?,
+        None => Uart::new(
+            peri,
+            rx_pin,
+            tx_pin,
+            Irqs,
+            tx_dma,
+            rx_dma,
+            Config::default(),
+        )?,
+    };
+
+    let (tx, rx) = uart.split();
+
+    Ok((
+        FeasycomBluetoothTx { tx, unanswered: 0 },
+        FeasycomBluetoothRx::from_rx(rx, flow_control),
+    ))
+}
+
+/// Opens TX on USART6 and RX on USART1, see `FeasycomBluetoothTx::new` and
+/// `FeasycomBluetoothRx::new`.
+#[cfg(not(feature = "single-usart"))]
+pub fn feasycom_bluetooth_new<'a>(
+    tx_peri: peripherals::USART6,
+    tx_pin: peripherals::PA11,
+    tx_dma: peripherals::DMA2_CH6,
+    rx_peri: peripherals::USART1,
+    rx_pin: peripherals::PB7,
+    rts_pin: Option<peripherals::PA12>,
+    rx_dma: peripherals::DMA2_CH2,
+) -> Result<(FeasycomBluetoothTx<'a>, FeasycomBluetoothRx<'a>), ConfigError> {
+    Ok((
+        FeasycomBluetoothTx::new(tx_peri, tx_pin, tx_dma)?,
+        FeasycomBluetoothRx::new(rx_peri, rx_pin, rts_pin, rx_dma)?,
+    ))
 }
 
 impl<'a> FeasycomBluetoothTx<'a> {
+    /// USART6 has no CTS pin on the F411CE package, so only the receive
+    /// direction is flow controlled.
+    #[cfg(not(feature = "single-usart"))]
     pub fn new(
         peri: peripherals::USART6,
         tx_pin: peripherals::PA11,
@@ -30,49 +163,270 @@ impl<'a> FeasycomBluetoothTx<'a> {
     ) -> Result<Self, ConfigError> {
         let tx = UartTx::new(peri, tx_pin, tx_dma, Config::default())?;
 
-        Ok(Self { tx })
+        Ok(Self { tx, unanswered: 0 })
     }
 
-    pub async fn write(&mut self, buffer: &[u8]) -> Result<(), usart::Error> {
+    /// Writes a command, or the command standing in for it, unless the
+    /// fitted module does not support it.
+    pub async fn write(&mut self, buffer: &[u8]) -> CommandResult {
+        let gate = app_state_with(|app_state| app_state.module.capabilities.gate(buffer));
+
+        let buffer = match gate {
+            CommandGate::Send => buffer,
+            CommandGate::Emulate(command) => command,
+            CommandGate::Reject => {
+                warn!("{} not supported", str::from_utf8(buffer).ok());
+                return Err(AtError::Unsupported);
+            }
+        };
+
         self.tx.write(buffer).await?;
 
+        #[cfg(feature = "uart-capture")]
+        uart_capture_record(Direction::Tx, buffer);
+
+        if buffer.starts_with(b"AT") {
+            self.unanswered = self.unanswered.saturating_add(1);
+        }
+
+        Ok(())
+    }
+
+    /// Writes bytes that are not a command, such as data in throughput mode,
+    /// so no answer is expected for them.
+    pub async fn write_raw(&mut self, buffer: &[u8]) -> Result<(), usart::Error> {
+        self.tx.write(buffer).await?;
+
+        #[cfg(feature = "uart-capture")]
+        uart_capture_record(Direction::Tx, buffer);
+
         Ok(())
     }
+
+    pub fn unanswered(&self) -> u8 {
+        self.unanswered
+    }
+
+    /// Called for every `OK` or `ERROR` received.
+    pub fn answered(&mut self) {
+        self.unanswered = self.unanswered.saturating_sub(1);
+    }
+
+    /// Forgets `count` unanswered commands whose answers were lost.
+    pub fn forget_unanswered(&mut self, count: u8) {
+        self.unanswered = self.unanswered.saturating_sub(count);
+    }
+
+    /// Forgets every unanswered command, after the module restarted.
+    pub fn clear_unanswered(&mut self) {
+        self.unanswered = 0;
+    }
+
+    /// Returns the module from throughput to AT mode, `AT+TPMODE=0` still
+    /// needs sending afterwards so it does not switch back on reconnect.
+    pub async fn escape_throughput_mode(&mut self) -> CommandResult {
+        Timer::after(ESCAPE_GUARD_TIME).await;
+        self.write_raw(ESCAPE_SEQUENCE).await?;
+        Timer::after(ESCAPE_GUARD_TIME).await;
+
+        Ok(())
+    }
+}
+
+impl<'a> FeasycomBluetoothControl<'a> {
+    /// Takes the pins with the module held in reset and switched off, until
+    /// `power_on` is called.
+    pub fn new(reset_pin: peripherals::PB0, power_pin: peripherals::PB1) -> Self {
+        Self {
+            reset: Output::new(reset_pin, Level::Low, Speed::Low),
+            power: Output::new(power_pin, Level::Low, Speed::Low),
+        }
+    }
+
+    /// Switches the module on and releases it from reset, whatever state it
+    /// was left in by a previous run of the firmware.
+    pub async fn power_on(&mut self) {
+        self.reset.set_low();
+        self.power.set_high();
+        Timer::after(POWER_SETTLE_TIME).await;
+        self.reset.set_high();
+    }
+
+    pub fn power_off(&mut self) {
+        self.reset.set_low();
+        self.power.set_low();
+    }
+
+    pub async fn power_cycle(&mut self) {
+        self.power_off();
+        Timer::after(POWER_OFF_TIME).await;
+        self.power_on().await;
+    }
+
+    pub async fn reset(&mut self) {
+        self.reset.set_low();
+        Timer::after(RESET_PULSE).await;
+        self.reset.set_high();
+    }
 }
 
 impl<'a> FeasycomBluetoothRx<'a> {
+    /// With `rts_pin` wired to the module's CTS input the module pauses while
+    /// the receiver is not keeping up, the module's own flow control has to be
+    /// enabled to match, see `flow_control`.
+    #[cfg(not(feature = "single-usart"))]
     pub fn new(
         peri: peripherals::USART1,
         rx_pin: peripherals::PB7,
+        rts_pin: Option<peripherals::PA12>,
         rx_dma: peripherals::DMA2_CH2,
     ) -> Result<Self, ConfigError> {
-        let rx = UartRx::new(peri, Irqs, rx_pin, rx_dma, Config::default())?
-            .into_ring_buffered(Box::leak(vec![0; RING_BUFFER_SIZE].into_boxed_slice()));
+        let flow_control = rts_pin.is_some();
+
+        let rx = match rts_pin {
+            Some(rts_pin) => {
+                UartRx::new_with_rts(peri, Irqs, rx_pin, rts_pin, rx_dma, Config::default())?
+            }
+            None => UartRx::new(peri, Irqs, rx_pin, rx_dma, Config::default())?,
+        };
+
+        Ok(Self::from_rx(rx, flow_control))
+    }
 
-        Ok(Self {
-            rx,
+    fn from_rx(
+        rx: UartRx<'a, peripherals::USART1, peripherals::DMA2_CH2>,
+        flow_control: bool,
+    ) -> Self {
+        Self {
+            rx: rx.into_ring_buffered(Box::leak(vec![0; RING_BUFFER_SIZE].into_boxed_slice())),
+            flow_control,
             buf: [0u8; RING_BUFFER_SIZE / 2],
-            msg: Vec::new(),
-        })
+            buf_pos: 0,
+            buf_len: 0,
+            framer: LineFramer::new(),
+        }
+    }
+
+    /// Whether the transport uses hardware flow control, the module must be
+    /// configured with `AT+UARTCFG` to match.
+    pub fn flow_control(&self) -> bool {
+        self.flow_control
     }
 
-    pub async fn read(&mut self) -> Result<Vec<u8>, usart::Error> {
+    /// Returns the next non-empty line. Errors are recoverable, reading again
+    /// carries on from the next complete line.
+    pub async fn read(&mut self) -> Result<Vec<u8>, RxError> {
         loop {
-            if let Some(msg_len) = self.msg[0..]
-                .windows(2)
-                .position(|x| x == b"\r\n")
-                .map(|x| x + 2)
+            while self.buf_pos < self.buf_len {
+                let byte = self.buf[self.buf_pos];
+                self.buf_pos += 1;
+
+                if let Some(frame) = self.framer.push(byte) {
+                    return frame.map(<[u8]>::to_vec);
+                }
+            }
+
+            self.buf_pos = 0;
+            self.buf_len = 0;
+            self.buf_len = self.fill().await?;
+        }
+    }
+
+    /// Reads bytes without any line framing, for use in throughput mode.
+    /// Anything received but not yet framed is returned first.
+    pub async fn read_raw(&mut self, buffer: &mut [u8]) -> Result<usize, RxError> {
+        if self.buf_pos < self.buf_len {
+            let len = buffer.len().min(self.buf_len - self.buf_pos);
+            buffer[..len].copy_from_slice(&self.buf[self.buf_pos..self.buf_pos + len]);
+            self.buf_pos += len;
+
+            return Ok(len);
+        }
+
+        let len = self.rx.read(buffer).await?;
+
+        #[cfg(feature = "uart-capture")]
+        uart_capture_record(Direction::Rx, &buffer[..len]);
+
+        Ok(len)
+    }
+
+    pub fn clear(&mut self) {
+        self.buf_pos = 0;
+        self.buf_len = 0;
+        self.framer.clear();
+    }
+
+    async fn fill(&mut self) -> Result<usize, RxError> {
+        match self.rx.read(&mut self.buf).await {
+            Ok(len) => {
+                #[cfg(feature = "uart-capture")]
+                uart_capture_record(Direction::Rx, &self.buf[..len]);
+
+                Ok(len)
+            }
+            Err(e) => {
+                // Bytes are missing, so drop everything up to the next
+                // terminator rather than framing a corrupt line.
+                self.framer.resync();
+                Err(e.into())
+            }
+        }
+    }
+
+    /// Waits for the module to finish booting, signalled by its power on
+    /// banner or, as not every firmware prints one, the first `OK` to the
+    /// `AT` probes sent meanwhile. Returns false if neither arrives in time.
+    pub async fn wait_for_boot(
+        &mut self,
+        feasycom_bluetooth_tx: &mut FeasycomBluetoothTx<'_>,
+        timeout: Duration,
+    ) -> bool {
+        let deadline = Instant::now() + timeout;
+
+        self.clear();
+
+        // Answers to commands written before the restart will never come.
+        feasycom_bluetooth_tx.clear_unanswered();
+
+        while Instant::now() < deadline {
+            // Written raw so the probes are not counted as unanswered, their
+            // answers are drained here.
+            if let Err(e) = feasycom_bluetooth_tx
+                .write_raw(command::At::new().as_bytes())
+                .await
             {
-                let msg = self.msg.drain(0..msg_len);
-                let line = msg.as_slice().trim_ascii();
+                error!("{}", e);
+            }
+
+            let probe_deadline = deadline.min(Instant::now() + BOOT_PROBE_INTERVAL);
 
-                if line.len() > 0 {
-                    return Ok(line.to_vec());
+            // Anything else is left over from before the module restarted,
+            // e.g. a status report or the answer to an earlier command.
+            loop {
+                match select(self.read(), Timer::at(probe_deadline)).await {
+                    Either::First(Ok(line)) if line == BOOT_BANNER || line == b"OK" => {
+                        self.drain(BOOT_QUIET_TIME).await;
+                        return true;
+                    }
+                    Either::First(Ok(_)) => {}
+                    Either::First(Err(e)) => error!("{}", e),
+                    Either::Second(()) => break,
                 }
             }
+        }
+
+        false
+    }
 
-            let len = self.rx.read(&mut self.buf).await?;
-            self.msg.extend(self.buf.iter().take(len));
+    /// Drops lines until none has arrived for `quiet`.
+    async fn drain(&mut self, quiet: Duration) {
+        while let Either::First(result) = select(self.read(), Timer::after(quiet)).await {
+            if let Err(e) = result {
+                error!("{}", e);
+            }
         }
+
+        self.clear();
     }
 }
@@ -0,0 +1,225 @@
+use defmt::Format;
+
+use crate::{
+    command_queue::command_name,
+    feasycom_protocol::indication::{FirmwareVersion, Ver},
+};
+
+pub const A2DP_SINK: u16 = 1 << 0;
+pub const A2DP_SOURCE: u16 = 1 << 1;
+pub const AVRCP: u16 = 1 << 2;
+pub const HFP: u16 = 1 << 3;
+pub const PBAP: u16 = 1 << 4;
+pub const SPP: u16 = 1 << 5;
+pub const GATT: u16 = 1 << 6;
+pub const THROUGHPUT: u16 = 1 << 7;
+pub const APTX: u16 = 1 << 8;
+pub const APTX_HD: u16 = 1 << 9;
+pub const LDAC: u16 = 1 << 10;
+pub const SPDIF: u16 = 1 << 11;
+/// `AT+A2DPSTAT` and `AT+HFPSTAT`, older firmware only reports the status of
+/// every profile at once through `AT+STAT`.
+pub const PROFILE_STAT: u16 = 1 << 12;
+
+const ALL: u16 = u16::MAX;
+
+/// First firmware to answer the per profile status queries, older firmware
+/// answers them with `ERROR`. See the revision history of the Feasycom audio
+/// module AT command manual.
+const PROFILE_STAT_FIRMWARE: FirmwareVersion = FirmwareVersion::new(3, 0, 0);
+
+/// The Feasycom modules fitted to the boards so far.
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Format)]
+pub enum ModuleModel {
+    Bt1006,
+    Bt1026,
+    Bt1036,
+    Unknown,
+}
+
+impl ModuleModel {
+    /// Matches the model reported by `+VER`, ignoring the `FSC-` prefix and
+    /// revision letters.
+    pub fn of(model: &str) -> Self {
+        if model.contains("BT1006") {
+            Self::Bt1006
+        } else if model.contains("BT1026") {
+            Self::Bt1026
+        } else if model.contains("BT1036") {
+            Self::Bt1036
+        } else {
+            Self::Unknown
+        }
+    }
+
+    /// From the profile and codec tables of each module's datasheet, see
+    /// the FSC-BT1006A, FSC-BT1026 and FSC-BT1036B datasheets on
+    /// feasycom.com. Phonebook access is listed under the supported profiles
+    /// as PBAP, the S/PDIF input under the audio interfaces.
+    fn capabilities(self) -> u16 {
+        let profiles = match self {
+            // Sink only, no PBAP and SBC/AAC only.
+            Self::Bt1006 => A2DP_SINK | AVRCP | HFP | SPP | GATT | THROUGHPUT,
+            // Source and sink, aptX but no aptX HD, LDAC or S/PDIF.
+            Self::Bt1026 => {
+                A2DP_SINK | A2DP_SOURCE | AVRCP | HFP | PBAP | SPP | GATT | THROUGHPUT | APTX
+            }
+            // Adds aptX HD, LDAC and the S/PDIF input.
+            Self::Bt1036 => {
+                A2DP_SINK
+                    | A2DP_SOURCE
+                    | AVRCP
+                    | HFP
+                    | PBAP
+                    | SPP
+                    | GATT
+                    | THROUGHPUT
+                    | APTX
+                    | APTX_HD
+                    | LDAC
+                    | SPDIF
+            }
+            // Everything is tried and left to the module to reject.
+            Self::Unknown => return ALL,
+        };
+
+        // Subject to the firmware version, see `Capabilities::of`.
+        profiles | PROFILE_STAT
+    }
+}
+
+/// What a command sent through the command queue becomes on this module.
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Format)]
+pub enum CommandGate {
+    Send,
+    /// Sent as this command instead, which answers with the same indications.
+    Emulate(&'static [u8]),
+    Reject,
+}
+
+/// Profiles, codecs and commands supported by the fitted module, everything
+/// is assumed supported until `+VER` is received.
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Format)]
+pub struct Capabilities {
+    model: ModuleModel,
+    firmware: Option<FirmwareVersion>,
+    supported: u16,
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Capabilities {
+    pub const fn new() -> Self {
+        Self {
+            model: ModuleModel::Unknown,
+            firmware: None,
+            supported: ALL,
+        }
+    }
+
+    pub fn of(ver: &Ver) -> Self {
+        let model = ModuleModel::of(&ver.model);
+        let mut supported = model.capabilities();
+
+        // Without a version the firmware is assumed recent.
+        if ver
+            .firmware
+            .is_some_and(|firmware| firmware < PROFILE_STAT_FIRMWARE)
+        {
+            supported &= !PROFILE_STAT;
+        }
+
+        Self {
+            model,
+            firmware: ver.firmware,
+            supported,
+        }
+    }
+
+    pub fn model(&self) -> ModuleModel {
+        self.model
+    }
+
+    pub fn firmware(&self) -> Option<FirmwareVersion> {
+        self.firmware
+    }
+
+    pub fn supports(&self, capabilities: u16) -> bool {
+        self.supported & capabilities == capabilities
+    }
+
+    pub fn gate(&self, command: &[u8]) -> CommandGate {
+        let name = command_name(command);
+
+        if matches!(name, b"AT+A2DPSTAT" | b"AT+HFPSTAT") && !self.supports(PROFILE_STAT) {
+            return CommandGate::Emulate(b"AT+STAT\r\n");
+        }
+
+        let required = match name {
+            b"AT+A2DPROLE" => A2DP_SOURCE,
+            b"AT+PBDOWN" => PBAP,
+            b"AT+TPMODE" => THROUGHPUT,
+            b"AT+SPDIFCFG" => SPDIF,
+            b"AT+LECFG" => GATT,
+            b"AT+MUTEMIC" => HFP,
+            b"AT+AVRCPCFG" | b"AT+PLAYPAUSE" | b"AT+PLAY" | b"AT+PAUSE" | b"AT+STOP"
+            | b"AT+FORWARD" | b"AT+BACKWARD" | b"AT+TRACKSTAT" => AVRCP,
+            name if name.starts_with(b"AT+HFP") => HFP,
+            name if name.starts_with(b"AT+SPP") => SPP,
+            name if name.starts_with(b"AT+GATT") => GATT,
+            _ => 0,
+        };
+
+        if self.supports(required) {
+            CommandGate::Send
+        } else {
+            CommandGate::Reject
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn capabilities(ver: &[u8]) -> Capabilities {
+        Capabilities::of(&Ver::try_from(ver).unwrap())
+    }
+
+    #[test]
+    fn everything_is_sent_until_the_version_is_known() {
+        let capabilities = Capabilities::new();
+
+        assert_eq!(capabilities.gate(b"AT+PBDOWN=0,256\r\n"), CommandGate::Send);
+        assert_eq!(capabilities.gate(b"AT+A2DPSTAT\r\n"), CommandGate::Send);
+    }
+
+    #[test]
+    fn unsupported_profiles_are_rejected() {
+        let capabilities = capabilities(b"FSC-BT1006A-V3.1.0");
+
+        assert_eq!(capabilities.model(), ModuleModel::Bt1006);
+        assert_eq!(
+            capabilities.gate(b"AT+PBDOWN=0,256\r\n"),
+            CommandGate::Reject
+        );
+        assert_eq!(capabilities.gate(b"AT+A2DPROLE=1\r\n"), CommandGate::Reject);
+        assert_eq!(capabilities.gate(b"AT+HFPSTAT\r\n"), CommandGate::Send);
+        assert_eq!(capabilities.gate(b"AT+VER\r\n"), CommandGate::Send);
+    }
+
+    #[test]
+    fn old_firmware_is_asked_for_every_status() {
+        let capabilities = capabilities(b"BT1026_V2.1");
+
+        assert_eq!(
+            capabilities.gate(b"AT+A2DPSTAT\r\n"),
+            CommandGate::Emulate(b"AT+STAT\r\n")
+        );
+        assert_eq!(capabilities.gate(b"AT+SPDIFCFG=1\r\n"), CommandGate::Reject);
+    }
+}
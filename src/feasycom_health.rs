@@ -0,0 +1,278 @@
+use defmt::Format;
+use embassy_time::{Duration, Instant};
+
+use crate::feasycom_protocol::indication::Indication;
+
+/// How long the module may stay silent before it is pinged with `AT+VER`.
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+const PING_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The ping is queued like any other command, so may wait behind the longest,
+/// `AT+PBDOWN`, before it is written.
+const PING_SEND_TIMEOUT: Duration = Duration::from_secs(32);
+
+/// Consecutive UART errors after which the module is considered unhealthy.
+const MAX_TRANSPORT_ERRORS: u8 = 5;
+
+/// Time given to the module to come back after a recovery before it is
+/// pinged again.
+const RECOVERY_GRACE: Duration = Duration::from_secs(10);
+
+/// Once reprovisioning has not helped, the module is only reset, waiting
+/// twice as long after each attempt up to `MAX_BACKOFF`.
+const MAX_BACKOFF: Duration = Duration::from_secs(600);
+
+/// Recovery actions, in the order they are escalated through while the
+/// module stays unhealthy. The module is reprovisioned at most once until it
+/// answers again, after which it is reset with a growing delay.
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Format)]
+pub enum Recovery {
+    /// `AT+REBOOT`, for a module that still parses commands.
+    Reboot,
+    /// Pulses the reset pin, for a module that no longer does.
+    HardwareReset,
+    /// Resets the module, restores its factory settings and configures it
+    /// again from scratch.
+    Reprovision,
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Format)]
+pub enum HealthAction {
+    Ping,
+    Recover(Recovery),
+}
+
+/// Tracks whether the module is still answering, deciding when to ping it and
+/// how to recover when it does not.
+pub struct FeasycomHealth {
+    ping_due: Instant,
+    ping_posted: Option<Instant>,
+    ping_sent: Option<Instant>,
+    transport_errors: u8,
+    recovery: Option<Recovery>,
+    reprovisioned: bool,
+    backoff: Duration,
+}
+
+impl FeasycomHealth {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            ping_due: now + PING_INTERVAL,
+            ping_posted: None,
+            ping_sent: None,
+            transport_errors: 0,
+            recovery: None,
+            reprovisioned: false,
+            backoff: RECOVERY_GRACE,
+        }
+    }
+
+    /// The last recovery attempted, cleared once the module answers again.
+    pub fn recovery(&self) -> Option<Recovery> {
+        self.recovery
+    }
+
+    pub fn deadline(&self) -> Instant {
+        match (self.ping_posted, self.ping_sent) {
+            (_, Some(ping_sent)) => ping_sent + PING_TIMEOUT,
+            (Some(ping_posted), None) => ping_posted + PING_SEND_TIMEOUT,
+            (None, None) => self.ping_due,
+        }
+    }
+
+    /// Called whenever `AT+VER` is written, the ping is only timed from then
+    /// as it may have waited behind a long command.
+    pub fn pinged(&mut self, now: Instant) {
+        if self.ping_posted.take().is_some() {
+            self.ping_sent = Some(now);
+        }
+    }
+
+    /// Any line parsed from the module shows it is alive, returns whether this
+    /// ends a recovery. Only `+VER` answers the ping, an `OK` may be the late
+    /// answer to something else.
+    pub fn indication(&mut self, indication: &Indication, now: Instant) -> bool {
+        self.transport_errors = 0;
+
+        // Only an answer to a ping proves the module accepts commands again,
+        // so while recovering the ping is not postponed.
+        if self.recovery.is_none() {
+            self.ping_due = now + PING_INTERVAL;
+        }
+
+        if self.ping_sent.is_some() && matches!(indication, Indication::Ver(_)) {
+            self.ping_sent = None;
+            self.ping_due = now + PING_INTERVAL;
+            self.reprovisioned = false;
+            self.backoff = RECOVERY_GRACE;
+            return self.recovery.take().is_some();
+        }
+
+        false
+    }
+
+    pub fn transport_error(&mut self, now: Instant) -> Option<Recovery> {
+        self.transport_errors += 1;
+
+        (self.transport_errors >= MAX_TRANSPORT_ERRORS).then(|| self.escalate(now))
+    }
+
+    /// Called once `deadline` has passed.
+    pub fn timeout(&mut self, now: Instant) -> HealthAction {
+        if self.ping_posted.is_some() || self.ping_sent.is_some() {
+            return HealthAction::Recover(self.escalate(now));
+        }
+
+        self.ping_posted = Some(now);
+        HealthAction::Ping
+    }
+
+    fn escalate(&mut self, now: Instant) -> Recovery {
+        let recovery = match self.recovery {
+            None => Recovery::Reboot,
+            Some(Recovery::Reboot) => Recovery::HardwareReset,
+            Some(Recovery::HardwareReset) if !self.reprovisioned => Recovery::Reprovision,
+            Some(Recovery::HardwareReset | Recovery::Reprovision) => Recovery::HardwareReset,
+        };
+
+        let grace = match recovery {
+            Recovery::HardwareReset if self.reprovisioned => {
+                let backoff = self.backoff;
+                self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+                backoff
+            }
+            _ => RECOVERY_GRACE,
+        };
+
+        self.reprovisioned |= recovery == Recovery::Reprovision;
+        self.recovery = Some(recovery);
+        self.ping_posted = None;
+        self.ping_sent = None;
+        self.transport_errors = 0;
+        self.ping_due = now + grace;
+
+        recovery
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::feasycom_protocol::indication::Ver;
+
+    /// Lets the ping go unanswered, returning the recovery and the time given
+    /// to the module before it is pinged again.
+    fn fail(feasycom_health: &mut FeasycomHealth) -> (Recovery, Duration) {
+        let now = feasycom_health.deadline();
+        assert_eq!(feasycom_health.timeout(now), HealthAction::Ping);
+        feasycom_health.pinged(now);
+
+        let now = feasycom_health.deadline();
+        let HealthAction::Recover(recovery) = feasycom_health.timeout(now) else {
+            panic!("unanswered ping did not recover");
+        };
+
+        (recovery, feasycom_health.deadline() - now)
+    }
+
+    #[test]
+    fn reprovisions_once_then_backs_off() {
+        let mut feasycom_health = FeasycomHealth::new(Instant::from_secs(0));
+
+        assert_eq!(
+            fail(&mut feasycom_health),
+            (Recovery::Reboot, RECOVERY_GRACE)
+        );
+        assert_eq!(
+            fail(&mut feasycom_health),
+            (Recovery::HardwareReset, RECOVERY_GRACE)
+        );
+        assert_eq!(
+            fail(&mut feasycom_health),
+            (Recovery::Reprovision, RECOVERY_GRACE)
+        );
+        assert_eq!(
+            fail(&mut feasycom_health),
+            (Recovery::HardwareReset, RECOVERY_GRACE)
+        );
+        assert_eq!(
+            fail(&mut feasycom_health),
+            (Recovery::HardwareReset, RECOVERY_GRACE * 2)
+        );
+        assert_eq!(
+            fail(&mut feasycom_health),
+            (Recovery::HardwareReset, RECOVERY_GRACE * 4)
+        );
+
+        for _ in 0..8 {
+            fail(&mut feasycom_health);
+        }
+        assert_eq!(
+            fail(&mut feasycom_health),
+            (Recovery::HardwareReset, MAX_BACKOFF)
+        );
+    }
+
+    #[test]
+    fn only_ver_answers_the_ping() {
+        let mut feasycom_health = FeasycomHealth::new(Instant::from_secs(0));
+        fail(&mut feasycom_health);
+
+        let now = feasycom_health.deadline();
+        assert_eq!(feasycom_health.timeout(now), HealthAction::Ping);
+        feasycom_health.pinged(now);
+        assert!(!feasycom_health.indication(&Indication::Ok, now));
+        assert_eq!(feasycom_health.recovery(), Some(Recovery::Reboot));
+
+        let ver = Indication::Ver(Ver::try_from(&b"BT1026_V2.1"[..]).unwrap());
+        assert!(feasycom_health.indication(&ver, now));
+        assert_eq!(feasycom_health.recovery(), None);
+    }
+
+    #[test]
+    fn answering_again_allows_another_reprovision() {
+        let mut feasycom_health = FeasycomHealth::new(Instant::from_secs(0));
+
+        for _ in 0..4 {
+            fail(&mut feasycom_health);
+        }
+
+        let now = feasycom_health.deadline();
+        feasycom_health.timeout(now);
+        feasycom_health.pinged(now);
+        let ver = Indication::Ver(Ver::try_from(&b"BT1026_V2.1"[..]).unwrap());
+        assert!(feasycom_health.indication(&ver, now));
+
+        let recoveries = [(); 3].map(|_| fail(&mut feasycom_health).0);
+        assert_eq!(
+            recoveries,
+            [
+                Recovery::Reboot,
+                Recovery::HardwareReset,
+                Recovery::Reprovision
+            ]
+        );
+    }
+
+    #[test]
+    fn the_ping_is_timed_from_when_it_is_sent() {
+        let mut feasycom_health = FeasycomHealth::new(Instant::from_secs(0));
+
+        let posted = feasycom_health.deadline();
+        assert_eq!(feasycom_health.timeout(posted), HealthAction::Ping);
+        assert!(feasycom_health.deadline() > posted + PING_TIMEOUT);
+
+        // Held up behind `AT+PBDOWN`.
+        let sent = posted + Duration::from_secs(20);
+        feasycom_health.pinged(sent);
+        assert_eq!(feasycom_health.deadline(), sent + PING_TIMEOUT);
+
+        let ver = Indication::Ver(Ver::try_from(&b"BT1026_V2.1"[..]).unwrap());
+        assert!(!feasycom_health.indication(&ver, sent + Duration::from_secs(1)));
+        assert_eq!(feasycom_health.recovery(), None);
+        assert_eq!(
+            feasycom_health.deadline(),
+            sent + Duration::from_secs(1) + PING_INTERVAL
+        );
+    }
+}
@@ -71,6 +71,7 @@ command_with_required_parameters!(
 );
 
 command_with_optional_parameters!(
+    At => "AT",
     Ver => "AT+VER",
     Addr => "AT+ADDR",
     LeAddr => "AT+LEADDR",
@@ -117,6 +118,7 @@ command_with_optional_parameters!(
     Stop => "AT+STOP",
     Forward => "AT+FORWARD",
     Backward => "AT+BACKWARD",
+    TrackStat => "AT+TRACKSTAT",
     SppStat => "AT+SPPSTAT",
     SppDisc => "AT+SPPDISC",
     GattStat => "AT+GATTSTAT",
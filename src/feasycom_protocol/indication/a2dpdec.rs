@@ -0,0 +1,47 @@
+use core::str::{self};
+use defmt::Format;
+
+use super::{A2dpCodecType, ChannelMode, Error};
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Format)]
+pub struct A2dpCodec {
+    pub codec: A2dpCodecType,
+    pub sample_rate: Option<u32>,
+    pub channel_mode: Option<ChannelMode>,
+    pub bitpool: Option<u8>,
+    pub bitrate: Option<u32>,
+}
+
+impl TryFrom<&[u8]> for A2dpCodec {
+    type Error = Error;
+
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        let (codec, value) = value.split_once(|c| c == &b',').unwrap_or((value, &[]));
+        let (sample_rate, value) = value.split_once(|c| c == &b',').unwrap_or((value, &[]));
+        let (channel_mode, value) = value.split_once(|c| c == &b',').unwrap_or((value, &[]));
+
+        let codec = A2dpCodecType::try_from(codec)?;
+
+        // The last parameter is the bitpool for SBC and the bitrate in kbit/s
+        // for every other codec.
+        let (bitpool, bitrate) = match (codec, value.is_empty()) {
+            (_, true) => (None, None),
+            (A2dpCodecType::Sbc, false) => (Some(str::from_utf8(value)?.parse()?), None),
+            (_, false) => (None, Some(str::from_utf8(value)?.parse()?)),
+        };
+
+        Ok(Self {
+            codec,
+            sample_rate: match sample_rate {
+                b"" => None,
+                sample_rate => Some(str::from_utf8(sample_rate)?.parse()?),
+            },
+            channel_mode: match channel_mode {
+                b"" => None,
+                channel_mode => Some(ChannelMode::try_from(channel_mode)?),
+            },
+            bitpool,
+            bitrate,
+        })
+    }
+}
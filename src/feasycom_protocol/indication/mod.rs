@@ -3,6 +3,7 @@ use alloc::{
     vec::Vec,
 };
 use core::{
+    convert::Infallible,
     fmt::{self, Debug, Display},
     num::ParseIntError,
     str::{self, Utf8Error},
@@ -18,6 +19,16 @@ impl Display for InvalidVariantError {
     }
 }
 
+/// Data longer than its length prefix, the expected and received lengths.
+#[derive(Debug, Eq, PartialEq, Clone, Format)]
+pub struct DataLengthError(usize, usize);
+
+impl Display for DataLengthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "expected {} bytes of data, received {}", self.0, self.1)
+    }
+}
+
 macro_rules! define_error {
     ($($name:ident),+ $(,)?) => {
         #[derive(Debug, Eq, PartialEq, Clone)]
@@ -43,13 +54,24 @@ macro_rules! define_error {
     };
 }
 
-define_error!(Utf8Error, ParseIntError, InvalidVariantError);
+define_error!(
+    Utf8Error,
+    ParseIntError,
+    InvalidVariantError,
+    DataLengthError
+);
+
+impl From<Infallible> for Error {
+    fn from(value: Infallible) -> Self {
+        match value {}
+    }
+}
 
 macro_rules! string_indications {
     ($($name:ident),+ $(,)?) => {
         $(
             #[derive(Debug, Eq, PartialEq, Clone, Format)]
-            pub struct $name(String);
+            pub struct $name(pub String);
 
             impl TryFrom<&[u8]> for $name {
                 type Error = Utf8Error;
@@ -66,20 +88,74 @@ macro_rules! data_indications {
     ($($name:ident),+ $(,)?) => {
         $(
             #[derive(Debug, Eq, PartialEq, Clone, Format)]
-            pub struct $name(String);
+            pub struct $name(pub String);
 
             impl TryFrom<&[u8]> for $name {
-                type Error = Utf8Error;
+                type Error = Error;
 
                 fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
-                    let (len, data) = value.split_once(|c| c == &b',').unwrap_or((&[], value));
+                    Ok($name(str::from_utf8(split_data(value)?)?.to_string()))
+                }
+            }
+        )?
+    };
+}
 
-                    // TODO if len is provided perform check and error
+/// Strips the `len,` prefix from data received from a peer, the data itself
+/// may contain commas. The line framer trims trailing whitespace, so the data
+/// may be shorter than its prefix but never longer.
+fn split_data(value: &[u8]) -> Result<&[u8], DataLengthError> {
+    let Some((len, data)) = value.split_once(|c| c == &b',') else {
+        return Ok(value);
+    };
+
+    if len.is_empty() || !len.iter().all(u8::is_ascii_digit) {
+        return Ok(value);
+    }
+
+    let len = len.iter().fold(0usize, |len, digit| {
+        len.saturating_mul(10)
+            .saturating_add((digit - b'0') as usize)
+    });
+
+    if data.len() > len {
+        return Err(DataLengthError(len, data.len()));
+    }
+
+    Ok(data)
+}
 
-                    Ok($name(str::from_utf8(data)?.to_string()))
+macro_rules! number_indications {
+    ($($name:ident: $size:ty),+ $(,)?) => {
+        $(
+            #[derive(Debug, Eq, PartialEq, Copy, Clone, Format)]
+            pub struct $name(pub $size);
+
+            impl TryFrom<&[u8]> for $name {
+                type Error = Error;
+
+                fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+                    Ok($name(str::from_utf8(value)?.parse()?))
                 }
             }
-        )?
+        )+
+    };
+}
+
+macro_rules! unit_indications {
+    ($($name:ident),+ $(,)?) => {
+        $(
+            #[derive(Debug, Eq, PartialEq, Copy, Clone, Format)]
+            pub struct $name;
+
+            impl TryFrom<&[u8]> for $name {
+                type Error = Infallible;
+
+                fn try_from(_: &[u8]) -> Result<Self, Self::Error> {
+                    Ok($name)
+                }
+            }
+        )+
     };
 }
 
@@ -105,9 +181,22 @@ macro_rules! enum_indications {
     };
 }
 
-string_indications!(A2dpDev, Addr, GattDev, LeAddr, SppDev, Ver);
-data_indications!(GattData, SppData);
+string_indications!(A2dpDev, Addr, GattDev, HfpCid, LeAddr, SppDev);
+data_indications!(GattData, PbData, SppData);
+number_indications!(SpkVol: u8);
+unit_indications!(HfpRing);
 enum_indications!(
+    A2dpCodecType {
+        b"0" => Sbc,
+        b"1" => Aac,
+        b"2" => AptX,
+        b"3" => AptXHd,
+        b"4" => Ldac,
+    },
+    A2dpRole {
+        b"0" => Sink,
+        b"1" => Source,
+    },
     A2dpStat {
         b"0" => Unsupported,
         b"1" => Standby,
@@ -121,12 +210,32 @@ enum_indications!(
         b"2" => Connecting,
         b"3" => Connected,
     },
+    ChannelMode {
+        b"0" => Mono,
+        b"1" => DualChannel,
+        b"2" => Stereo,
+        b"3" => JointStereo,
+    },
     GattStat {
         b"0" => Unsupported,
         b"1" => Standby,
         b"2" => Connecting,
         b"3" => Connected,
     },
+    HfpAudio {
+        b"0" => Phone,
+        b"1" => Device,
+    },
+    HfpStat {
+        b"0" => Unsupported,
+        b"1" => Standby,
+        b"2" => Connecting,
+        b"3" => Connected,
+        b"4" => OutgoingCall,
+        b"5" => IncomingCall,
+        b"6" => ActiveCall,
+        b"7" => HeldCall,
+    },
     PlayStat {
         b"0" => Stopped,
         b"1" => Playing,
@@ -142,11 +251,17 @@ enum_indications!(
     },
 );
 
+mod a2dpdec;
+mod scan;
 mod trackinfo;
 mod trackstat;
+mod ver;
 
+pub use a2dpdec::A2dpCodec;
+pub use scan::ScanResult;
 pub use trackinfo::TrackInfo;
 pub use trackstat::TrackStat;
+pub use ver::{FirmwareVersion, Ver};
 
 macro_rules! indications {
     ($($bytes:literal => $type:ident),+ $(,)?) => {
@@ -199,7 +314,15 @@ indications!(
     b"+LEADDR" => LeAddr,
     b"+A2DPSTAT" => A2dpStat,
     b"+A2DPDEV" => A2dpDev,
+    b"+A2DPDEC" => A2dpCodec,
+    b"+A2DPROLE" => A2dpRole,
+    b"+SCAN" => ScanResult,
     b"+AVRCPSTAT" => AvrcpStat,
+    b"+HFPSTAT" => HfpStat,
+    b"+HFPRING" => HfpRing,
+    b"+HFPCID" => HfpCid,
+    b"+HFPAUDIO" => HfpAudio,
+    b"+SPKVOL" => SpkVol,
     b"+PLAYSTAT" => PlayStat,
     b"+TRACKSTAT" => TrackStat,
     b"+TRACKINFO" => TrackInfo,
@@ -209,4 +332,46 @@ indications!(
     b"+GATTDEV" => GattDev,
     b"+SPPDATA" => SppData,
     b"+GATTDATA" => GattData,
+    b"+PBDATA" => PbData,
 );
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn data_keeps_commas_after_length_prefix() {
+        assert_eq!(
+            Indication::try_from(&b"+PBDATA=41,item1.TEL;type=WORK,VOICE:+61 2 9999 0000"[..]),
+            Ok(Indication::PbData(PbData(
+                "item1.TEL;type=WORK,VOICE:+61 2 9999 0000".to_string()
+            )))
+        );
+    }
+
+    #[test]
+    fn data_without_length_prefix_is_kept_whole() {
+        assert_eq!(
+            Indication::try_from(&b"+PBDATA=TEL;type=WORK,VOICE:555"[..]),
+            Ok(Indication::PbData(PbData(
+                "TEL;type=WORK,VOICE:555".to_string()
+            )))
+        );
+    }
+
+    #[test]
+    fn data_keeps_leading_whitespace_of_folded_lines() {
+        assert_eq!(
+            Indication::try_from(&b"+PBDATA=8, Smith,"[..]),
+            Ok(Indication::PbData(PbData(" Smith,".to_string())))
+        );
+    }
+
+    #[test]
+    fn data_longer_than_its_prefix_is_rejected() {
+        assert_eq!(
+            Indication::try_from(&b"+SPPDATA=2,abc"[..]),
+            Err(Error::DataLengthError(DataLengthError(2, 3)))
+        );
+    }
+}
@@ -0,0 +1,27 @@
+use alloc::string::{String, ToString};
+use core::str::{self};
+use defmt::Format;
+
+use super::Error;
+
+#[derive(Debug, Eq, PartialEq, Clone, Format)]
+pub struct ScanResult {
+    pub mac: String,
+    pub rssi: i16,
+    pub name: String,
+}
+
+impl TryFrom<&[u8]> for ScanResult {
+    type Error = Error;
+
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        let (mac, value) = value.split_once(|c| c == &b',').unwrap_or((value, &[]));
+        let (rssi, name) = value.split_once(|c| c == &b',').unwrap_or((value, &[]));
+
+        Ok(Self {
+            mac: str::from_utf8(mac)?.to_string(),
+            rssi: str::from_utf8(rssi)?.parse()?,
+            name: str::from_utf8(name)?.to_string(),
+        })
+    }
+}
@@ -0,0 +1,74 @@
+use alloc::string::{String, ToString};
+use core::str::{self, Utf8Error};
+use defmt::Format;
+
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Copy, Clone, Format)]
+pub struct FirmwareVersion {
+    pub major: u8,
+    pub minor: u8,
+    pub patch: u8,
+}
+
+impl FirmwareVersion {
+    pub const fn new(major: u8, minor: u8, patch: u8) -> Self {
+        Self {
+            major,
+            minor,
+            patch,
+        }
+    }
+}
+
+/// The module model and firmware version, reported as e.g.
+/// `FSC-BT1036B-V3.2.5` or `BT1026_V2.1`. A version that cannot be made out
+/// is left as `None` with the whole report kept as the model.
+#[derive(Debug, Eq, PartialEq, Clone, Format)]
+pub struct Ver {
+    pub model: String,
+    pub firmware: Option<FirmwareVersion>,
+}
+
+impl TryFrom<&[u8]> for Ver {
+    type Error = Utf8Error;
+
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        let value = str::from_utf8(value)?.trim();
+
+        // The version is the last `V` following a separator and followed by
+        // a digit, models such as `FSC-BT1006V` may end in a `V` themselves.
+        let split = value.char_indices().rev().find(|(i, c)| {
+            matches!(c, 'V' | 'v')
+                && value[..*i].ends_with(['-', '_', ' '])
+                && value[i + 1..].starts_with(|c: char| c.is_ascii_digit())
+        });
+
+        let Some((i, _)) = split else {
+            return Ok(Self {
+                model: value.to_string(),
+                firmware: None,
+            });
+        };
+
+        Ok(Self {
+            model: value[..i - 1].to_string(),
+            firmware: parse_firmware(&value[i + 1..]),
+        })
+    }
+}
+
+/// Parses `major.minor[.patch]`, ignoring any suffix such as `_beta`.
+fn parse_firmware(value: &str) -> Option<FirmwareVersion> {
+    let mut parts = value.split('.').map(|part| {
+        let end = part
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(part.len());
+
+        part[..end].parse::<u8>().ok()
+    });
+
+    Some(FirmwareVersion {
+        major: parts.next()??,
+        minor: parts.next()??,
+        patch: parts.next().flatten().unwrap_or(0),
+    })
+}
@@ -0,0 +1,400 @@
+use core::cell::Cell;
+use defmt::{info, Format};
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::pubsub::{self, PubSubChannel, Subscriber};
+use embassy_time::{Duration, Instant};
+
+use crate::{
+    app_state::{app_state_update, update},
+    feasycom_protocol::{
+        command,
+        indication::{A2dpStat, AvrcpStat, HfpStat, Indication},
+    },
+    TaskRawMutex,
+};
+
+/// Covers waiting for the module to boot as well as the `+VER` answer to the
+/// configuration written after it.
+const BOOTING_TIMEOUT: Duration = Duration::from_secs(5);
+const CONFIGURING_TIMEOUT: Duration = Duration::from_secs(5);
+const CONNECTING_TIMEOUT: Duration = Duration::from_secs(15);
+
+const TRANSITION_CAPACITY: usize = 4;
+const TRANSITION_SUBSCRIBERS: usize = 4;
+
+static FEASYCOM_STATE: Mutex<TaskRawMutex, Cell<FeasycomState>> =
+    Mutex::new(Cell::new(FeasycomState::PoweredOff));
+
+static FEASYCOM_TRANSITION_CHANNEL: PubSubChannel<
+    TaskRawMutex,
+    FeasycomTransition,
+    TRANSITION_CAPACITY,
+    TRANSITION_SUBSCRIBERS,
+    1,
+> = PubSubChannel::new();
+
+pub type FeasycomTransitionSubscriber = Subscriber<
+    'static,
+    TaskRawMutex,
+    FeasycomTransition,
+    TRANSITION_CAPACITY,
+    TRANSITION_SUBSCRIBERS,
+    1,
+>;
+
+pub fn feasycom_state_get() -> FeasycomState {
+    FEASYCOM_STATE.lock(|state| state.get())
+}
+
+pub fn feasycom_state_set(transition: FeasycomTransition) {
+    FEASYCOM_STATE.lock(|state| state.set(transition.to));
+    app_state_update(|app_state| update(&mut app_state.module.state, transition.to));
+    FEASYCOM_TRANSITION_CHANNEL
+        .immediate_publisher()
+        .publish_immediate(transition);
+}
+
+pub fn feasycom_transitions() -> Result<FeasycomTransitionSubscriber, pubsub::Error> {
+    FEASYCOM_TRANSITION_CHANNEL.subscriber()
+}
+
+/// Resolves once the module is in `state`, immediately if it already is.
+pub async fn feasycom_state_wait_for(state: FeasycomState) -> Result<(), pubsub::Error> {
+    let mut transitions = feasycom_transitions()?;
+
+    if feasycom_state_get() == state {
+        return Ok(());
+    }
+
+    while transitions.next_message_pure().await.to != state {}
+
+    Ok(())
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Format)]
+pub enum FeasycomState {
+    PoweredOff,
+    Booting,
+    Configuring,
+    Discoverable,
+    Connecting,
+    Connected,
+    Streaming,
+    Error,
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Format)]
+pub struct FeasycomTransition {
+    pub from: FeasycomState,
+    pub to: FeasycomState,
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Format)]
+pub enum FeasycomEvent<'a> {
+    Reset,
+    BluetoothEnabled(bool),
+    PairingEnabled(bool),
+    Indication(&'a Indication),
+    Timeout,
+}
+
+impl FeasycomEvent<'static> {
+    /// The event standing for a command the module answered `OK` to.
+    pub fn answered(command: &[u8]) -> Option<Self> {
+        [true, false].into_iter().find_map(|enabled| {
+            if command == command::BtEn::new().enable_bluetooth(enabled).as_bytes() {
+                Some(Self::BluetoothEnabled(enabled))
+            } else if command == command::Pair::new().enable_pairing(enabled).as_bytes() {
+                Some(Self::PairingEnabled(enabled))
+            } else {
+                None
+            }
+        })
+    }
+}
+
+pub struct FeasycomStateMachine {
+    state: FeasycomState,
+    entered: Instant,
+    enabled: bool,
+    a2dp_stat: A2dpStat,
+    avrcp_stat: AvrcpStat,
+    hfp_stat: HfpStat,
+}
+
+impl Default for FeasycomStateMachine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FeasycomStateMachine {
+    pub fn new() -> Self {
+        Self {
+            state: FeasycomState::PoweredOff,
+            entered: Instant::now(),
+            enabled: true,
+            a2dp_stat: A2dpStat::Unsupported,
+            avrcp_stat: AvrcpStat::Unsupported,
+            hfp_stat: HfpStat::Unsupported,
+        }
+    }
+
+    pub fn state(&self) -> FeasycomState {
+        self.state
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        let timeout = match self.state {
+            FeasycomState::Booting => BOOTING_TIMEOUT,
+            FeasycomState::Configuring => CONFIGURING_TIMEOUT,
+            FeasycomState::Connecting => CONNECTING_TIMEOUT,
+            _ => return None,
+        };
+
+        Some(self.entered + timeout)
+    }
+
+    /// Handles the event and publishes the resulting transition, if any.
+    pub fn dispatch(&mut self, event: FeasycomEvent) {
+        self.dispatch_at(event, Instant::now());
+    }
+
+    /// Like `dispatch`, for events replayed at the time they were captured.
+    pub fn dispatch_at(&mut self, event: FeasycomEvent, now: Instant) {
+        if let Some(transition) = self.handle(event, now) {
+            info!("{}", transition);
+            feasycom_state_set(transition);
+        }
+    }
+
+    pub fn handle(&mut self, event: FeasycomEvent, now: Instant) -> Option<FeasycomTransition> {
+        let from = self.state;
+        let to = self.next(event);
+
+        self.state = to;
+
+        if to != from || matches!(event, FeasycomEvent::Reset) {
+            self.entered = now;
+        }
+
+        (to != from).then_some(FeasycomTransition { from, to })
+    }
+
+    fn next(&mut self, event: FeasycomEvent) -> FeasycomState {
+        use FeasycomState::*;
+
+        match (self.state, event) {
+            (_, FeasycomEvent::Reset) => {
+                *self = Self {
+                    enabled: self.enabled,
+                    ..Self::new()
+                };
+                Booting
+            }
+
+            // `AT+VER` is the last command of the configuration, so its
+            // answer completes it. Answers to the commands before it are
+            // seen while still booting.
+            (Booting, FeasycomEvent::Indication(Indication::Ver(_))) => Configuring,
+            (Configuring, FeasycomEvent::Indication(Indication::Ok)) => self.connection_state(),
+            (Configuring, FeasycomEvent::Indication(Indication::Err)) => Error,
+            (Booting | Configuring, FeasycomEvent::Timeout) => Error,
+
+            (_, FeasycomEvent::BluetoothEnabled(enabled)) => {
+                self.enabled = enabled;
+                self.connection_state()
+            }
+
+            (Error | Discoverable, FeasycomEvent::PairingEnabled(true)) => self.connection_state(),
+
+            (state, FeasycomEvent::Indication(indication)) => {
+                match indication {
+                    Indication::A2dpStat(a2dp_stat) => self.a2dp_stat = *a2dp_stat,
+                    Indication::AvrcpStat(avrcp_stat) => self.avrcp_stat = *avrcp_stat,
+                    Indication::HfpStat(hfp_stat) => self.hfp_stat = *hfp_stat,
+                    _ => return state,
+                }
+
+                // A module that reports its profiles again is working, so
+                // this also leaves `Error`.
+                match state {
+                    Discoverable | Connecting | Connected | Streaming | Error => {
+                        self.connection_state()
+                    }
+                    state => state,
+                }
+            }
+
+            // A connection attempt that never completes leaves the profiles
+            // reporting connecting, give up on it and become discoverable.
+            (Connecting, FeasycomEvent::Timeout) => {
+                if self.a2dp_stat == A2dpStat::Connecting {
+                    self.a2dp_stat = A2dpStat::Standby;
+                }
+                if self.avrcp_stat == AvrcpStat::Connecting {
+                    self.avrcp_stat = AvrcpStat::Standby;
+                }
+                if self.hfp_stat == HfpStat::Connecting {
+                    self.hfp_stat = HfpStat::Standby;
+                }
+                self.connection_state()
+            }
+
+            (state, _) => state,
+        }
+    }
+
+    fn connection_state(&self) -> FeasycomState {
+        if !self.enabled {
+            return FeasycomState::PoweredOff;
+        }
+
+        if self.a2dp_stat == A2dpStat::Streaming {
+            return FeasycomState::Streaming;
+        }
+
+        if self.a2dp_stat == A2dpStat::Connected
+            || self.avrcp_stat == AvrcpStat::Connected
+            || matches!(
+                self.hfp_stat,
+                HfpStat::Connected
+                    | HfpStat::OutgoingCall
+                    | HfpStat::IncomingCall
+                    | HfpStat::ActiveCall
+                    | HfpStat::HeldCall
+            )
+        {
+            return FeasycomState::Connected;
+        }
+
+        if self.a2dp_stat == A2dpStat::Connecting
+            || self.avrcp_stat == AvrcpStat::Connecting
+            || self.hfp_stat == HfpStat::Connecting
+        {
+            return FeasycomState::Connecting;
+        }
+
+        FeasycomState::Discoverable
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use FeasycomState::*;
+
+    enum Step {
+        Reset,
+        Line(&'static [u8]),
+        Timeout,
+        BluetoothEnabled(bool),
+        PairingEnabled(bool),
+    }
+
+    fn run(state_machine: &mut FeasycomStateMachine, step: &Step) -> Option<FeasycomTransition> {
+        let now = Instant::from_secs(0);
+
+        match step {
+            Step::Reset => state_machine.handle(FeasycomEvent::Reset, now),
+            Step::Line(line) => {
+                let indication = Indication::try_from(*line).unwrap();
+                state_machine.handle(FeasycomEvent::Indication(&indication), now)
+            }
+            Step::Timeout => state_machine.handle(FeasycomEvent::Timeout, now),
+            Step::BluetoothEnabled(enabled) => {
+                state_machine.handle(FeasycomEvent::BluetoothEnabled(*enabled), now)
+            }
+            Step::PairingEnabled(enabled) => {
+                state_machine.handle(FeasycomEvent::PairingEnabled(*enabled), now)
+            }
+        }
+    }
+
+    #[test]
+    fn transitions() {
+        #[rustfmt::skip]
+        let cases: &[(&str, &[Step], FeasycomState)] = &[
+            ("reset boots", &[Step::Reset], Booting),
+            ("answers before +VER keep booting", &[Step::Reset, Step::Line(b"OK"), Step::Line(b"ERROR")], Booting),
+            ("+VER starts configuring", &[Step::Reset, Step::Line(b"+VER=FSC-BT1036B-V3.2.5")], Configuring),
+            ("OK to AT+VER completes it", &[Step::Reset, Step::Line(b"+VER=FSC-BT1036B-V3.2.5"), Step::Line(b"OK")], Discoverable),
+            ("ERROR to AT+VER fails it", &[Step::Reset, Step::Line(b"+VER=FSC-BT1036B-V3.2.5"), Step::Line(b"ERROR")], Error),
+            ("no boot", &[Step::Reset, Step::Timeout], Error),
+            ("no configuration", &[Step::Reset, Step::Line(b"+VER=FSC-BT1036B-V3.2.5"), Step::Timeout], Error),
+            ("status while booting is kept", &[Step::Reset, Step::Line(b"+A2DPSTAT=3"), Step::Line(b"+VER=BT1026_V2.1"), Step::Line(b"OK")], Connected),
+            ("connecting", &[Step::Reset, Step::Line(b"+VER=BT1026_V2.1"), Step::Line(b"OK"), Step::Line(b"+HFPSTAT=2")], Connecting),
+            ("connecting gives up", &[Step::Reset, Step::Line(b"+VER=BT1026_V2.1"), Step::Line(b"OK"), Step::Line(b"+HFPSTAT=2"), Step::Timeout], Discoverable),
+            ("streaming", &[Step::Reset, Step::Line(b"+VER=BT1026_V2.1"), Step::Line(b"OK"), Step::Line(b"+A2DPSTAT=4")], Streaming),
+            ("call without media", &[Step::Reset, Step::Line(b"+VER=BT1026_V2.1"), Step::Line(b"OK"), Step::Line(b"+HFPSTAT=6")], Connected),
+            ("disconnected", &[Step::Reset, Step::Line(b"+VER=BT1026_V2.1"), Step::Line(b"OK"), Step::Line(b"+A2DPSTAT=4"), Step::Line(b"+A2DPSTAT=1")], Discoverable),
+            ("+VER once connected is a ping", &[Step::Reset, Step::Line(b"+VER=BT1026_V2.1"), Step::Line(b"OK"), Step::Line(b"+VER=BT1026_V2.1"), Step::Line(b"OK")], Discoverable),
+            ("bluetooth off", &[Step::Reset, Step::Line(b"+VER=BT1026_V2.1"), Step::Line(b"OK"), Step::BluetoothEnabled(false)], PoweredOff),
+            ("bluetooth off survives a reset", &[Step::BluetoothEnabled(false), Step::Reset, Step::Line(b"+VER=BT1026_V2.1"), Step::Line(b"OK")], PoweredOff),
+            ("bluetooth back on", &[Step::Reset, Step::Line(b"+VER=BT1026_V2.1"), Step::Line(b"OK"), Step::BluetoothEnabled(false), Step::BluetoothEnabled(true)], Discoverable),
+            ("status leaves error", &[Step::Reset, Step::Timeout, Step::Line(b"+A2DPSTAT=1")], Discoverable),
+            ("pairing leaves error", &[Step::Reset, Step::Timeout, Step::PairingEnabled(true)], Discoverable),
+            ("pairing off stays in error", &[Step::Reset, Step::Timeout, Step::PairingEnabled(false)], Error),
+            ("reset leaves error", &[Step::Reset, Step::Timeout, Step::Reset], Booting),
+        ];
+
+        for (name, steps, expected) in cases {
+            let mut state_machine = FeasycomStateMachine::new();
+
+            for step in *steps {
+                run(&mut state_machine, step);
+            }
+
+            assert_eq!(state_machine.state(), *expected, "{name}");
+        }
+    }
+
+    #[test]
+    fn transition_is_reported_once() {
+        let mut state_machine = FeasycomStateMachine::new();
+
+        assert_eq!(
+            run(&mut state_machine, &Step::Reset),
+            Some(FeasycomTransition {
+                from: PoweredOff,
+                to: Booting
+            })
+        );
+        assert_eq!(run(&mut state_machine, &Step::Line(b"OK")), None);
+        assert_eq!(run(&mut state_machine, &Step::Reset), None);
+    }
+
+    #[test]
+    fn deadline_follows_the_state() {
+        let mut state_machine = FeasycomStateMachine::new();
+        assert_eq!(state_machine.deadline(), None);
+
+        let now = Instant::from_secs(100);
+        state_machine.handle(FeasycomEvent::Reset, now);
+        assert_eq!(state_machine.deadline(), Some(now + BOOTING_TIMEOUT));
+
+        let indication = Indication::try_from(&b"+VER=BT1026_V2.1"[..]).unwrap();
+        let later = Instant::from_secs(101);
+        state_machine.handle(FeasycomEvent::Indication(&indication), later);
+        assert_eq!(state_machine.deadline(), Some(later + CONFIGURING_TIMEOUT));
+
+        state_machine.handle(FeasycomEvent::Indication(&Indication::Ok), later);
+        assert_eq!(state_machine.deadline(), None);
+    }
+
+    #[test]
+    fn answered_commands() {
+        assert_eq!(
+            FeasycomEvent::answered(command::BtEn::new().enable_bluetooth(false).as_bytes()),
+            Some(FeasycomEvent::BluetoothEnabled(false))
+        );
+        assert_eq!(
+            FeasycomEvent::answered(command::Pair::new().enable_pairing(true).as_bytes()),
+            Some(FeasycomEvent::PairingEnabled(true))
+        );
+        assert_eq!(
+            FeasycomEvent::answered(command::Ver::new().as_bytes()),
+            None
+        );
+    }
+}
@@ -1,32 +1,283 @@
-use alloc::{string::ToString, vec::Vec};
-use defmt::{error, info};
-use embassy_stm32::peripherals;
+use defmt::{error, info, warn};
+use embassy_futures::select::{select3, select4, Either3, Either4};
+use embassy_time::{Duration, Instant, Timer};
 
 use crate::{
-    feasycom_bluetooth::{FeasycomBluetoothRx, FeasycomBluetoothTx},
-    feasycom_protocol::{command, indication::Indication},
+    a2dp_source::a2dp_source_setup,
+    app_state::{app_state_update, update, ModuleError},
+    command_queue::{command_name, AtError, CommandArbiter, CommandPriority, CommandResult},
+    feasycom_bluetooth::{FeasycomBluetoothControl, FeasycomBluetoothRx, FeasycomBluetoothTx},
+    feasycom_health::{FeasycomHealth, HealthAction, Recovery},
+    feasycom_protocol::{
+        command,
+        indication::{A2dpStat, HfpStat, Indication},
+    },
+    feasycom_state::{FeasycomEvent, FeasycomStateMachine},
+    line_framer::RxError,
+    line_handler::handle_line,
+    phonebook::{
+        contact_index::{
+            contact_index_clear, contact_index_insert, contact_index_set_country_code,
+            CONTACT_INDEX_CAPACITY,
+        },
+        vcard::VCardParser,
+    },
+    playback_events::PlaybackTracker,
+    settings::settings_load,
+    status_poll::{StatusPoller, STATUS_POLL_INTERVAL},
+    throughput::{throughput_request_get, throughput_run},
+    volume::{volume_request, Volume, VolumeRequest, VolumeStep},
 };
 
-enum FeasycomState {}
-
-impl FeasycomState {}
+/// Time the module may take to boot after it is powered on or reset.
+const BOOT_TIMEOUT: Duration = Duration::from_secs(3);
 
 #[embassy_executor::task]
 pub async fn feasycom_task(
-    tx_peri: peripherals::USART6,
-    tx_pin: peripherals::PA11,
-    tx_dma: peripherals::DMA2_CH6,
-    rx_peri: peripherals::USART1,
-    rx_pin: peripherals::PB7,
-    rx_dma: peripherals::DMA2_CH2,
+    mut feasycom_bluetooth_tx: FeasycomBluetoothTx<'static>,
+    mut feasycom_bluetooth_rx: FeasycomBluetoothRx<'static>,
+    mut feasycom_bluetooth_control: FeasycomBluetoothControl<'static>,
 ) -> ! {
-    let mut feasycom_bluetooth_tx = FeasycomBluetoothTx::new(tx_peri, tx_pin, tx_dma).unwrap();
-    let mut feasycom_bluetooth_rx = FeasycomBluetoothRx::new(rx_peri, rx_pin, rx_dma).unwrap();
+    let mut feasycom_state_machine = FeasycomStateMachine::new();
+    feasycom_state_machine.dispatch(FeasycomEvent::Reset);
 
-    feasycom_bluetooth_tx
-        .write(command::Ver::new().as_bytes())
+    feasycom_bluetooth_control.power_on().await;
+
+    // Nothing is sent until the module has booted, however long after the
+    // MCU it came up.
+    if !boot(&mut feasycom_bluetooth_tx, &mut feasycom_bluetooth_rx).await {
+        feasycom_state_machine.dispatch(FeasycomEvent::Timeout);
+    }
+
+    let mut vcard_parser = VCardParser::new();
+    let mut playback_tracker = PlaybackTracker::new();
+    let mut a2dp_stat = A2dpStat::Unsupported;
+    let mut hfp_stat = HfpStat::Unsupported;
+    let settings = settings_load().await;
+    contact_index_set_country_code(settings.country_code);
+
+    let mut volume = Volume::new(settings.volume_limit);
+    let mut status_poller = StatusPoller::new(STATUS_POLL_INTERVAL);
+    let mut feasycom_health = FeasycomHealth::new(Instant::now());
+    let mut pending_recovery = None;
+    let mut command_arbiter = CommandArbiter::new();
+
+    step_volume(&mut command_arbiter, &volume);
+
+    loop {
+        if let Some(recovery) = pending_recovery.take() {
+            warn!("module unresponsive, attempting {}", recovery);
+            app_state_update(|app_state| update(&mut app_state.module.recovery, Some(recovery)));
+
+            feasycom_state_machine.dispatch(FeasycomEvent::Reset);
+            command_arbiter.reset();
+            app_state_update(|app_state| app_state.restarted());
+
+            if !recover(
+                &mut feasycom_bluetooth_tx,
+                &mut feasycom_bluetooth_rx,
+                &mut feasycom_bluetooth_control,
+                recovery,
+            )
+            .await
+            {
+                feasycom_state_machine.dispatch(FeasycomEvent::Timeout);
+            }
+
+            a2dp_stat = A2dpStat::Unsupported;
+            hfp_stat = HfpStat::Unsupported;
+            volume = Volume::new(volume.limit());
+            step_volume(&mut command_arbiter, &volume);
+        }
+
+        let command_deadline = command_arbiter.deadline();
+
+        let msg = match select4(
+            feasycom_bluetooth_rx.read(),
+            select3(
+                volume_request(),
+                throughput_request_get(),
+                command_arbiter.next(),
+            ),
+            timeout_at(feasycom_state_machine.deadline()),
+            select3(
+                Timer::at(status_poller.deadline()),
+                Timer::at(feasycom_health.deadline()),
+                timeout_at(command_deadline),
+            ),
+        )
         .await
-        .unwrap();
+        {
+            Either4::First(Ok(msg)) => msg,
+            Either4::First(Err(e)) => {
+                error!("{}", e);
+                app_state_update(|app_state| {
+                    update(&mut app_state.module.error, Some(ModuleError::Transport(e)))
+                });
+
+                // Overruns and over-long lines are resynchronised by the
+                // framer, only errors from the UART itself point at the module.
+                if let RxError::Usart(_) = e {
+                    pending_recovery = feasycom_health.transport_error(Instant::now());
+                }
+                continue;
+            }
+            Either4::Second(Either3::First(request)) => {
+                match request {
+                    VolumeRequest::Level(level) => volume.set_target(level),
+                    VolumeRequest::Limit(limit) => volume.set_limit(limit),
+                }
+
+                step_volume(&mut command_arbiter, &volume);
+                continue;
+            }
+            Either4::Second(Either3::Second(true)) => {
+                throughput_run(&mut feasycom_bluetooth_tx, &mut feasycom_bluetooth_rx).await;
+                continue;
+            }
+            Either4::Second(Either3::Second(false)) => continue,
+            Either4::Second(Either3::Third(request)) => {
+                // Only the answer is timed out, cutting the write short would
+                // leave half a command on the line. A write held up by the
+                // module's CTS completes once it reads again.
+                match feasycom_bluetooth_tx.write(request.command()).await {
+                    Ok(()) => {
+                        if request.command() == command::Ver::new().as_bytes() {
+                            feasycom_health.pinged(Instant::now());
+                        }
+
+                        let answers_ahead = feasycom_bluetooth_tx.unanswered().saturating_sub(1);
+                        let deadline = Instant::now() + request.timeout();
+                        command_arbiter.sent(request, answers_ahead, deadline);
+                    }
+                    Err(AtError::Transport(e)) => {
+                        error!("{}", e);
+                        command_arbiter.failed(request, AtError::Transport(e));
+                    }
+                    Err(e) => request.complete(Err(e)),
+                }
+                continue;
+            }
+            Either4::Third(()) => {
+                feasycom_state_machine.dispatch(FeasycomEvent::Timeout);
+                continue;
+            }
+            Either4::Fourth(Either3::First(())) => {
+                status_poller.poll(
+                    &mut command_arbiter,
+                    feasycom_state_machine.state(),
+                    Instant::now(),
+                );
+                continue;
+            }
+            Either4::Fourth(Either3::Second(())) => {
+                match feasycom_health.timeout(Instant::now()) {
+                    HealthAction::Ping => command_arbiter
+                        .post(CommandPriority::Normal, command::Ver::new().as_bytes()),
+                    HealthAction::Recover(recovery) => pending_recovery = Some(recovery),
+                }
+                continue;
+            }
+            Either4::Fourth(Either3::Third(())) => {
+                warn!("command timed out");
+
+                // Every answer due is taken as lost, the arbiter holds the
+                // next command back long enough for a late one to be dropped.
+                let lost = command_arbiter.timeout();
+                feasycom_bluetooth_tx.forget_unanswered(lost);
+                continue;
+            }
+        };
+
+        let now = Instant::now();
+
+        status_poller.traffic(now);
+
+        let Some(indication) = handle_line(
+            &msg,
+            &mut feasycom_state_machine,
+            &mut playback_tracker,
+            now,
+            |indication, app_state| status_poller.reconcile(indication, app_state),
+        ) else {
+            continue;
+        };
+
+        if feasycom_health.indication(&indication, Instant::now()) {
+            info!("module recovered");
+            app_state_update(|app_state| update(&mut app_state.module.recovery, None));
+        }
+
+        if matches!(indication, Indication::Ok | Indication::Err) {
+            let answered = command_arbiter.answered(&indication);
+            feasycom_bluetooth_tx.answered();
+
+            if let Some(event) = answered
+                .as_deref()
+                .filter(|_| indication == Indication::Ok)
+                .and_then(FeasycomEvent::answered)
+            {
+                feasycom_state_machine.dispatch(event);
+            }
+
+            // `AT+PBDOWN` answers once the download is over, which may have
+            // stopped part way through the last contact.
+            if answered.as_deref().map(command_name) == Some(b"AT+PBDOWN") {
+                if let Some(contact) = vcard_parser.finish() {
+                    info!("{}", contact);
+                    contact_index_insert(&contact);
+                }
+            }
+        }
+
+        if let Indication::PbData(data) = &indication {
+            if let Some(contact) = vcard_parser.push_line(data.0.as_bytes()) {
+                info!("{}", contact);
+                contact_index_insert(&contact);
+            }
+        }
+
+        if let Indication::A2dpStat(next_a2dp_stat) = indication {
+            let previous_a2dp_stat = core::mem::replace(&mut a2dp_stat, next_a2dp_stat);
+
+            if next_a2dp_stat == A2dpStat::Streaming && previous_a2dp_stat != A2dpStat::Streaming {
+                command_arbiter.post(CommandPriority::Normal, command::A2dpDec::new().as_bytes());
+            }
+        }
+
+        if let Indication::HfpStat(next_hfp_stat) = indication {
+            let previous_hfp_stat = core::mem::replace(&mut hfp_stat, next_hfp_stat);
+
+            if next_hfp_stat == HfpStat::Connected
+                && matches!(
+                    previous_hfp_stat,
+                    HfpStat::Unsupported | HfpStat::Standby | HfpStat::Connecting
+                )
+            {
+                download_phonebook(&mut command_arbiter, &mut vcard_parser);
+            }
+        }
+
+        if let Indication::SpkVol(level) = indication {
+            volume.update(level.0);
+            step_volume(&mut command_arbiter, &volume);
+        }
+    }
+}
+
+async fn configure(
+    feasycom_bluetooth_tx: &mut FeasycomBluetoothTx<'_>,
+    flow_control: bool,
+) -> CommandResult {
+    // Sent on every boot as the module keeps the setting across resets,
+    // while the board may have been flashed with a different transport.
+    feasycom_bluetooth_tx
+        .write(
+            command::UartCfg::new()
+                .enable_cts_rts(flow_control)
+                .as_bytes(),
+        )
+        .await?;
 
     feasycom_bluetooth_tx
         .write(
@@ -35,8 +286,7 @@ pub async fn feasycom_task(
                 .enable_suffix(false)
                 .as_bytes(),
         )
-        .await
-        .unwrap();
+        .await?;
 
     feasycom_bluetooth_tx
         .write(
@@ -45,26 +295,111 @@ pub async fn feasycom_task(
                 .enable_suffix(false)
                 .as_bytes(),
         )
+        .await?;
+
+    for command in a2dp_source_setup(&settings_load().await) {
+        match feasycom_bluetooth_tx.write(&command).await {
+            // The role is best effort, the module comes up without it.
+            Err(AtError::Unsupported) => warn!("not supported by the module"),
+            result => result?,
+        }
+    }
+
+    // Written last, the state machine takes its answer as the end of the
+    // configuration.
+    feasycom_bluetooth_tx
+        .write(command::Ver::new().as_bytes())
         .await
-        .unwrap();
+}
 
-    loop {
-        let msg = match feasycom_bluetooth_rx.read().await {
-            Ok(msg) => msg,
-            Err(e) => {
+async fn recover(
+    feasycom_bluetooth_tx: &mut FeasycomBluetoothTx<'_>,
+    feasycom_bluetooth_rx: &mut FeasycomBluetoothRx<'_>,
+    feasycom_bluetooth_control: &mut FeasycomBluetoothControl<'_>,
+    recovery: Recovery,
+) -> bool {
+    match recovery {
+        Recovery::Reboot => {
+            if let Err(e) = feasycom_bluetooth_tx
+                .write(command::Reboot::new().as_bytes())
+                .await
+            {
                 error!("{}", e);
-                continue;
             }
-        };
+        }
+        Recovery::HardwareReset => feasycom_bluetooth_control.reset().await,
+        Recovery::Reprovision => {
+            feasycom_bluetooth_control.power_cycle().await;
 
-        let indication = match Indication::try_from(msg) {
-            Ok(indication) => indication,
-            Err(e) => {
-                error!("{}", defmt::Debug2Format(&e));
-                continue;
+            if !feasycom_bluetooth_rx
+                .wait_for_boot(feasycom_bluetooth_tx, BOOT_TIMEOUT)
+                .await
+            {
+                return false;
             }
-        };
 
-        info!("{}", indication);
+            // Restoring the factory settings reboots the module again.
+            if let Err(e) = feasycom_bluetooth_tx
+                .write(command::Restore::new().as_bytes())
+                .await
+            {
+                error!("{}", e);
+            }
+        }
+    }
+
+    boot(feasycom_bluetooth_tx, feasycom_bluetooth_rx).await
+}
+
+/// Waits for the module to boot then configures it, returning `false` if it
+/// did not boot. The state machine follows the configuration from the
+/// answers to it.
+async fn boot(
+    feasycom_bluetooth_tx: &mut FeasycomBluetoothTx<'_>,
+    feasycom_bluetooth_rx: &mut FeasycomBluetoothRx<'_>,
+) -> bool {
+    if !feasycom_bluetooth_rx
+        .wait_for_boot(feasycom_bluetooth_tx, BOOT_TIMEOUT)
+        .await
+    {
+        warn!("module did not boot");
+        return false;
+    }
+
+    if let Err(e) = configure(feasycom_bluetooth_tx, feasycom_bluetooth_rx.flow_control()).await {
+        error!("{}", e);
+    }
+
+    true
+}
+
+async fn timeout_at(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => Timer::at(deadline).await,
+        None => core::future::pending().await,
     }
 }
+
+fn step_volume(command_arbiter: &mut CommandArbiter, volume: &Volume) {
+    let command = match volume.next_step() {
+        Some(VolumeStep::Query) => command::SpkVol::new().as_bytes().to_vec(),
+        Some(VolumeStep::Increase) => command::SpkVol::new().increase().as_bytes().to_vec(),
+        Some(VolumeStep::Decrease) => command::SpkVol::new().decrease().as_bytes().to_vec(),
+        None => return,
+    };
+
+    command_arbiter.post(CommandPriority::High, &command);
+}
+
+fn download_phonebook(command_arbiter: &mut CommandArbiter, vcard_parser: &mut VCardParser) {
+    contact_index_clear();
+    *vcard_parser = VCardParser::new();
+
+    command_arbiter.post(
+        CommandPriority::Low,
+        command::PbDown::new()
+            .phonebook(0)
+            .max_items(CONTACT_INDEX_CAPACITY as u16)
+            .as_bytes(),
+    );
+}
@@ -0,0 +1,209 @@
+use core::cell::Cell;
+use defmt::Format;
+use embassy_sync::{blocking_mutex::Mutex, channel::Channel, pubsub};
+
+use crate::{feasycom_protocol::indication::Indication, TaskRawMutex};
+
+const INDICATION_SUBSCRIBERS: usize = 4;
+
+/// Indications of its classes a subscriber can fall behind by before the
+/// oldest are dropped. Publishing never waits, so a slow subscriber only lags
+/// itself.
+const INDICATION_CAPACITY: usize = 8;
+
+/// Every indication belongs to exactly one class, subscribers pick the
+/// classes they receive and skip the rest.
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Format)]
+pub enum IndicationClass {
+    /// Track, play status, volume and codec reports.
+    Playback,
+    /// Calls and the phonebook.
+    Hfp,
+    SppData,
+    GattData,
+    /// Replies to commands, profile status and everything else.
+    Status,
+}
+
+impl IndicationClass {
+    pub fn of(indication: &Indication) -> Self {
+        match indication {
+            Indication::PlayStat(_)
+            | Indication::TrackStat(_)
+            | Indication::TrackInfo(_)
+            | Indication::SpkVol(_)
+            | Indication::A2dpCodec(_) => Self::Playback,
+            Indication::HfpStat(_)
+            | Indication::HfpRing(_)
+            | Indication::HfpCid(_)
+            | Indication::HfpAudio(_)
+            | Indication::PbData(_) => Self::Hfp,
+            Indication::SppData(_) => Self::SppData,
+            Indication::GattData(_) => Self::GattData,
+            _ => Self::Status,
+        }
+    }
+
+    fn mask(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+/// Number of indications dropped because the subscriber fell behind.
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Format)]
+pub struct Lagged(pub u64);
+
+#[derive(Debug, Copy, Clone)]
+struct Slot {
+    /// Mask of subscribed classes, none for a free slot.
+    classes: u8,
+    lagged: u64,
+}
+
+const FREE: Slot = Slot {
+    classes: 0,
+    lagged: 0,
+};
+
+static INDICATION_SLOTS: Mutex<TaskRawMutex, Cell<[Slot; INDICATION_SUBSCRIBERS]>> =
+    Mutex::new(Cell::new([FREE; INDICATION_SUBSCRIBERS]));
+
+/// A queue per subscriber, only holding its classes so other traffic never
+/// pushes out what it has yet to read.
+static INDICATION_QUEUES: [Channel<TaskRawMutex, Indication, INDICATION_CAPACITY>;
+    INDICATION_SUBSCRIBERS] = [
+    Channel::new(),
+    Channel::new(),
+    Channel::new(),
+    Channel::new(),
+];
+
+/// Passes an indication to every subscriber of its class, called by
+/// `feasycom_task` for each line parsed.
+pub fn indication_publish(indication: &Indication) {
+    let mask = IndicationClass::of(indication).mask();
+
+    INDICATION_SLOTS.lock(|slots| {
+        let mut subscribers = slots.get();
+
+        for (slot, queue) in subscribers.iter_mut().zip(&INDICATION_QUEUES) {
+            if slot.classes & mask == 0 {
+                continue;
+            }
+
+            if queue.is_full() {
+                let _ = queue.try_receive();
+                slot.lagged += 1;
+            }
+
+            let _ = queue.try_send(indication.clone());
+        }
+
+        slots.set(subscribers);
+    });
+}
+
+pub fn indication_subscribe(
+    classes: &[IndicationClass],
+) -> Result<IndicationSubscriber, pubsub::Error> {
+    let classes = classes.iter().fold(0, |mask, class| mask | class.mask());
+
+    INDICATION_SLOTS.lock(|slots| {
+        let mut subscribers = slots.get();
+
+        let index = subscribers
+            .iter()
+            .position(|slot| slot.classes == 0)
+            .ok_or(pubsub::Error::MaximumSubscribersReached)?;
+
+        subscribers[index] = Slot { classes, lagged: 0 };
+        slots.set(subscribers);
+        INDICATION_QUEUES[index].clear();
+
+        Ok(IndicationSubscriber { index })
+    })
+}
+
+pub struct IndicationSubscriber {
+    index: usize,
+}
+
+impl IndicationSubscriber {
+    /// Waits for the next indication of a subscribed class, reporting first
+    /// how many were dropped if the subscriber fell behind.
+    pub async fn next(&mut self) -> Result<Indication, Lagged> {
+        self.lagged()?;
+
+        Ok(INDICATION_QUEUES[self.index].receive().await)
+    }
+
+    pub fn try_next(&mut self) -> Option<Result<Indication, Lagged>> {
+        if let Err(lagged) = self.lagged() {
+            return Some(Err(lagged));
+        }
+
+        INDICATION_QUEUES[self.index].try_receive().ok().map(Ok)
+    }
+
+    fn lagged(&self) -> Result<(), Lagged> {
+        INDICATION_SLOTS.lock(|slots| {
+            let mut subscribers = slots.get();
+            let lagged = core::mem::take(&mut subscribers[self.index].lagged);
+            slots.set(subscribers);
+
+            match lagged {
+                0 => Ok(()),
+                count => Err(Lagged(count)),
+            }
+        })
+    }
+}
+
+impl Drop for IndicationSubscriber {
+    fn drop(&mut self) {
+        INDICATION_SLOTS.lock(|slots| {
+            let mut subscribers = slots.get();
+            subscribers[self.index] = FREE;
+            slots.set(subscribers);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        feasycom_protocol::indication::{A2dpStat, PlayStat},
+        test_lock,
+    };
+
+    #[test]
+    fn subscribers_only_receive_their_classes() {
+        let _lock = test_lock();
+
+        let mut playback = indication_subscribe(&[IndicationClass::Playback]).unwrap();
+        let mut status = indication_subscribe(&[IndicationClass::Status]).unwrap();
+
+        indication_publish(&Indication::PlayStat(PlayStat::Playing));
+        indication_publish(&Indication::A2dpStat(A2dpStat::Streaming));
+
+        assert_eq!(
+            playback.try_next(),
+            Some(Ok(Indication::PlayStat(PlayStat::Playing)))
+        );
+        assert_eq!(playback.try_next(), None);
+        assert_eq!(
+            status.try_next(),
+            Some(Ok(Indication::A2dpStat(A2dpStat::Streaming)))
+        );
+        assert_eq!(status.try_next(), None);
+
+        for _ in 0..INDICATION_CAPACITY + 2 {
+            indication_publish(&Indication::Ok);
+        }
+
+        assert_eq!(status.try_next(), Some(Err(Lagged(2))));
+        assert_eq!(status.try_next(), Some(Ok(Indication::Ok)));
+        assert_eq!(playback.try_next(), None);
+    }
+}
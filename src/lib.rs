@@ -0,0 +1,65 @@
+//! The parts of the firmware that do not touch the hardware: the module's
+//! protocol, the state built from what it reports and the queues between
+//! tasks. They are built as a library so their tests run on the host:
+//!
+//! ```text
+//! cargo test --lib --target x86_64-unknown-linux-gnu
+//! ```
+
+#![cfg_attr(not(test), no_std)]
+#![feature(byte_slice_trim_ascii, slice_split_once)]
+
+extern crate alloc;
+
+pub mod a2dp_setup;
+pub mod app_state;
+pub mod call_state;
+pub mod command_queue;
+pub mod crc;
+pub mod feasycom_capabilities;
+pub mod feasycom_health;
+pub mod feasycom_protocol;
+pub mod feasycom_state;
+pub mod indication_bus;
+pub mod line_framer;
+pub mod line_handler;
+pub mod phonebook;
+pub mod playback_clock;
+pub mod playback_events;
+pub mod replay;
+pub mod store;
+pub mod volume;
+
+/// Mutex for state shared between tasks, which all run on the thread mode
+/// executor. Host tests run on several threads, so use a critical section.
+#[cfg(not(test))]
+pub type TaskRawMutex = embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+#[cfg(test)]
+pub type TaskRawMutex = embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+
+/// Serialises the tests that go through the statics shared between tasks.
+#[cfg(test)]
+fn test_lock() -> std::sync::MutexGuard<'static, ()> {
+    static LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+    LOCK.lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner)
+}
+
+#[cfg(not(test))]
+use embassy_stm32::usart;
+
+/// The UART errors of `embassy-stm32`, which does not build for the host.
+#[cfg(test)]
+mod usart {
+    use defmt::Format;
+
+    #[derive(Debug, Eq, PartialEq, Copy, Clone, Format)]
+    pub enum Error {
+        Framing,
+        Noise,
+        Overrun,
+        Parity,
+        BufferTooLong,
+    }
+}
@@ -0,0 +1,152 @@
+use defmt::Format;
+
+use crate::usart;
+
+/// Longest line kept by the framer, long enough for a `+PBDATA` vCard line or
+/// a `+TRACKINFO` with long titles.
+pub const LINE_CAPACITY: usize = 256;
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Format)]
+pub enum RxError {
+    Usart(usart::Error),
+    /// The ring buffer filled before it was read and bytes were lost, the
+    /// partially received line was discarded.
+    Overrun,
+    /// A line longer than `LINE_CAPACITY` was discarded up to its terminator.
+    LineTooLong,
+}
+
+impl From<usart::Error> for RxError {
+    fn from(value: usart::Error) -> Self {
+        match value {
+            usart::Error::Overrun => RxError::Overrun,
+            e => RxError::Usart(e),
+        }
+    }
+}
+
+/// Splits the byte stream into `\r\n` terminated lines in a fixed buffer,
+/// so garbage or a missing terminator cannot exhaust the heap.
+pub struct LineFramer<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+    discarding: bool,
+}
+
+impl<const N: usize> Default for LineFramer<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> LineFramer<N> {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; N],
+            len: 0,
+            discarding: false,
+        }
+    }
+
+    /// Returns a line, without surrounding whitespace, once its terminator is
+    /// pushed. An over-long line is reported as soon as it overflows and the
+    /// rest of it is dropped.
+    pub fn push(&mut self, byte: u8) -> Option<Result<&[u8], RxError>> {
+        if byte == b'\n' {
+            let len = core::mem::replace(&mut self.len, 0);
+
+            if core::mem::replace(&mut self.discarding, false) {
+                return None;
+            }
+
+            let line = self.buf[..len].trim_ascii();
+
+            return (!line.is_empty()).then_some(Ok(line));
+        }
+
+        if self.discarding {
+            return None;
+        }
+
+        if self.len == N {
+            self.len = 0;
+            self.discarding = true;
+
+            return Some(Err(RxError::LineTooLong));
+        }
+
+        self.buf[self.len] = byte;
+        self.len += 1;
+
+        None
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+        self.discarding = false;
+    }
+
+    /// Drops everything up to the next terminator, after bytes were lost.
+    pub fn resync(&mut self) {
+        self.len = 0;
+        self.discarding = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    /// Pushes every byte, collecting what the framer returns.
+    fn feed<const N: usize>(
+        framer: &mut LineFramer<N>,
+        bytes: &[u8],
+    ) -> Vec<Result<Vec<u8>, RxError>> {
+        bytes
+            .iter()
+            .filter_map(|byte| framer.push(*byte).map(|result| result.map(<[u8]>::to_vec)))
+            .collect()
+    }
+
+    #[test]
+    fn splits_on_crlf() {
+        let mut framer = LineFramer::<16>::new();
+
+        assert_eq!(
+            feed(&mut framer, b"OK\r\n\r\n+SPKVOL=10\r\n"),
+            [Ok(b"OK".to_vec()), Ok(b"+SPKVOL=10".to_vec())]
+        );
+    }
+
+    #[test]
+    fn lines_split_across_reads_are_joined() {
+        let mut framer = LineFramer::<16>::new();
+
+        assert_eq!(feed(&mut framer, b"+A2DP"), []);
+        assert_eq!(
+            feed(&mut framer, b"STAT=3\r\nO"),
+            [Ok(b"+A2DPSTAT=3".to_vec())]
+        );
+        assert_eq!(feed(&mut framer, b"K\r\n"), [Ok(b"OK".to_vec())]);
+    }
+
+    #[test]
+    fn overlong_lines_are_discarded_up_to_their_terminator() {
+        let mut framer = LineFramer::<4>::new();
+
+        assert_eq!(
+            feed(&mut framer, b"+TRACKINFO\r\nOK\r\n"),
+            [Err(RxError::LineTooLong), Ok(b"OK".to_vec())]
+        );
+    }
+
+    #[test]
+    fn resync_drops_the_partial_line() {
+        let mut framer = LineFramer::<16>::new();
+
+        assert_eq!(feed(&mut framer, b"+SPK"), []);
+        framer.resync();
+        assert_eq!(feed(&mut framer, b"VOL=10\r\nOK\r\n"), [Ok(b"OK".to_vec())]);
+    }
+}
@@ -0,0 +1,51 @@
+use defmt::{error, info};
+use embassy_time::Instant;
+
+use crate::{
+    app_state::{app_state_update, update, AppState, ModuleError},
+    feasycom_protocol::indication::Indication,
+    feasycom_state::{FeasycomEvent, FeasycomStateMachine},
+    indication_bus::indication_publish,
+    playback_events::{playback_event_publish, PlaybackTracker},
+};
+
+/// Parses a line from the module, whether read from the UART or replayed from
+/// a capture, and applies it to the state machine, the playback events, the
+/// app state and the indication bus. Returns the indication for the caller's
+/// own handling. `before_reduce` sees the app state as it was before it.
+pub fn handle_line(
+    line: &[u8],
+    feasycom_state_machine: &mut FeasycomStateMachine,
+    playback_tracker: &mut PlaybackTracker,
+    now: Instant,
+    before_reduce: impl FnOnce(&Indication, &AppState),
+) -> Option<Indication> {
+    let indication = match Indication::try_from(line) {
+        Ok(indication) => indication,
+        Err(e) => {
+            error!("{}", defmt::Debug2Format(&e));
+            app_state_update(|app_state| {
+                update(&mut app_state.module.error, Some(ModuleError::Parse))
+            });
+            return None;
+        }
+    };
+
+    info!("{}", indication);
+
+    feasycom_state_machine.dispatch_at(FeasycomEvent::Indication(&indication), now);
+
+    for playback_event in playback_tracker.next(&indication, now) {
+        info!("{}", playback_event);
+        playback_event_publish(playback_event);
+    }
+
+    app_state_update(|app_state| {
+        before_reduce(&indication, app_state);
+        app_state.reduce(&indication, now)
+    });
+
+    indication_publish(&indication);
+
+    Some(indication)
+}
@@ -1,20 +1,72 @@
 #![no_main]
 #![no_std]
-#![feature(byte_slice_trim_ascii, slice_split_once)]
 
 extern crate alloc;
 extern crate defmt_rtt;
 extern crate panic_probe;
 
-mod app_state;
+// Replaying a capture stands in for the module and everything driving it.
+#[cfg(not(feature = "uart-replay"))]
+mod a2dp_source;
+#[cfg(not(feature = "uart-replay"))]
 mod feasycom_bluetooth;
-mod feasycom_protocol;
+#[cfg(not(feature = "uart-replay"))]
 mod feasycom_task;
 mod piicodev_oled;
+#[cfg(not(feature = "uart-replay"))]
+mod scrobble;
+#[cfg(not(feature = "uart-replay"))]
+mod settings;
+#[cfg(not(feature = "uart-replay"))]
+mod spp;
+#[cfg(not(feature = "uart-replay"))]
+mod spp_shell;
+#[cfg(not(feature = "uart-replay"))]
+mod status_poll;
+#[cfg(not(feature = "uart-replay"))]
+mod storage;
+#[cfg(not(feature = "uart-replay"))]
+mod throughput;
+#[cfg(all(feature = "uart-capture", not(feature = "uart-replay")))]
+mod uart_capture;
+#[cfg(feature = "uart-replay")]
+mod uart_replay;
 
+#[cfg(not(feature = "uart-replay"))]
+use a2dp_source::a2dp_source_task;
+use bluetooth::app_state;
+#[cfg(feature = "uart-replay")]
+use bluetooth::replay;
+#[cfg(not(feature = "uart-replay"))]
+use bluetooth::{
+    a2dp_setup, command_queue, crc, feasycom_capabilities, feasycom_health, feasycom_protocol,
+    feasycom_state, indication_bus, line_framer, line_handler, phonebook, playback_events, volume,
+};
 use embassy_executor::Spawner;
+#[cfg(not(feature = "uart-replay"))]
+use embassy_stm32::bind_interrupts;
+#[cfg(not(feature = "uart-replay"))]
+use embassy_stm32::flash::{self, Flash};
+#[cfg(not(feature = "uart-replay"))]
+use embassy_stm32::rtc::{Rtc, RtcConfig};
 use embedded_alloc::Heap;
+#[cfg(not(feature = "uart-replay"))]
+use feasycom_bluetooth::{feasycom_bluetooth_new, FeasycomBluetoothControl};
+#[cfg(not(feature = "uart-replay"))]
 use feasycom_task::feasycom_task;
+#[cfg(not(feature = "uart-replay"))]
+use scrobble::{scrobble_init, scrobble_task};
+#[cfg(not(feature = "uart-replay"))]
+use spp_shell::spp_shell_task;
+#[cfg(not(feature = "uart-replay"))]
+use storage::storage_init;
+#[cfg(feature = "uart-replay")]
+use uart_replay::uart_replay_task;
+
+#[cfg(not(feature = "uart-replay"))]
+bind_interrupts!(struct Irqs {
+    FLASH => flash::InterruptHandler;
+});
 
 #[global_allocator]
 static HEAP: Heap = Heap::empty();
@@ -23,16 +75,69 @@ static HEAP: Heap = Heap::empty();
 async fn main(spawner: Spawner) {
     {
         use core::mem::MaybeUninit;
-        const HEAP_SIZE: usize = 1024;
+        // Commands, indications and contacts are allocated while in flight,
+        // a phonebook download keeps several of each alive at once.
+        const HEAP_SIZE: usize = 16 * 1024;
         static mut HEAP_MEM: [MaybeUninit<u8>; HEAP_SIZE] = [MaybeUninit::uninit(); HEAP_SIZE];
         unsafe { HEAP.init(HEAP_MEM.as_ptr() as usize, HEAP_SIZE) }
     }
 
     let p = embassy_stm32::init(Default::default());
 
+    // The module is left switched off, its lines come from the capture.
+    #[cfg(feature = "uart-replay")]
+    {
+        drop(p);
+
+        spawner
+            .spawn(uart_replay_task(include_str!(env!("UART_REPLAY_CAPTURE"))))
+            .unwrap();
+    }
+
+    #[cfg(not(feature = "uart-replay"))]
+    run(spawner, p).await;
+}
+
+#[cfg(not(feature = "uart-replay"))]
+async fn run(spawner: Spawner, p: embassy_stm32::Peripherals) {
+    storage_init(Flash::new(p.FLASH, Irqs)).await;
+    scrobble_init(Rtc::new(p.RTC, RtcConfig::default()));
+
+    #[cfg(not(feature = "single-usart"))]
+    let (feasycom_bluetooth_tx, feasycom_bluetooth_rx) = {
+        // Boards with the module's CTS input wired to PA12.
+        #[cfg(feature = "flow-control")]
+        let rts_pin = Some(p.PA12);
+        #[cfg(not(feature = "flow-control"))]
+        let rts_pin = None;
+
+        feasycom_bluetooth_new(
+            p.USART6, p.PA11, p.DMA2_CH6, p.USART1, p.PB7, rts_pin, p.DMA2_CH2,
+        )
+        .unwrap()
+    };
+
+    #[cfg(feature = "single-usart")]
+    let (feasycom_bluetooth_tx, feasycom_bluetooth_rx) = {
+        // Boards with the module's CTS and RTS wired to PA12 and PA11.
+        #[cfg(feature = "flow-control")]
+        let rts_cts_pins = Some((p.PA12, p.PA11));
+        #[cfg(not(feature = "flow-control"))]
+        let rts_cts_pins = None;
+
+        feasycom_bluetooth_new(p.USART1, p.PB6, p.PB7, rts_cts_pins, p.DMA2_CH7, p.DMA2_CH2)
+            .unwrap()
+    };
+
     spawner
         .spawn(feasycom_task(
-            p.USART6, p.PA11, p.DMA2_CH6, p.USART1, p.PB7, p.DMA2_CH2,
+            feasycom_bluetooth_tx,
+            feasycom_bluetooth_rx,
+            FeasycomBluetoothControl::new(p.PB0, p.PB1),
         ))
         .unwrap();
+
+    spawner.spawn(a2dp_source_task()).unwrap();
+    spawner.spawn(spp_shell_task()).unwrap();
+    spawner.spawn(scrobble_task()).unwrap();
 }
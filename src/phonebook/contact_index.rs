@@ -0,0 +1,328 @@
+use alloc::string::{String, ToString};
+use core::{cell::RefCell, str};
+use embassy_sync::blocking_mutex::Mutex;
+
+use super::vcard::Contact;
+use crate::TaskRawMutex;
+
+pub const CONTACT_INDEX_CAPACITY: usize = 256;
+pub const CONTACT_NAME_LEN: usize = 24;
+
+/// Country code of numbers written in national form, e.g. `0412 345 678`,
+/// until the user sets their own.
+pub const DEFAULT_COUNTRY_CODE: u16 = 61;
+pub const MAX_COUNTRY_CODE: u16 = 999;
+pub const TRUNK_PREFIX: &str = "0";
+pub const INTERNATIONAL_PREFIX: &str = "00";
+
+/// Longest number kept, E.164 allows 15 digits including the country code.
+const MAX_DIGITS: usize = 15;
+
+/// Trailing digits that must agree when a number is written without any
+/// prefix, e.g. `412345678`, so its country and trunk prefix are unknown.
+const MIN_MATCH_DIGITS: u8 = 7;
+
+static CONTACT_INDEX: Mutex<TaskRawMutex, RefCell<ContactIndex>> =
+    Mutex::new(RefCell::new(ContactIndex::new()));
+
+pub fn contact_index_insert(contact: &Contact) {
+    CONTACT_INDEX.lock(|contact_index| contact_index.borrow_mut().insert(contact));
+}
+
+pub fn contact_index_lookup(number: &str) -> Option<String> {
+    CONTACT_INDEX.lock(|contact_index| contact_index.borrow().lookup(number))
+}
+
+pub fn contact_index_clear() {
+    CONTACT_INDEX.lock(|contact_index| contact_index.borrow_mut().clear());
+}
+
+pub fn contact_index_set_country_code(country_code: u16) {
+    CONTACT_INDEX.lock(|contact_index| contact_index.borrow_mut().set_country_code(country_code));
+}
+
+/// How a number was written, which decides what it is compared by.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+enum Form {
+    /// With `+` or the international prefix.
+    International,
+    /// With the trunk prefix, in the country of `country_code`.
+    National,
+    /// Without any prefix.
+    Local,
+}
+
+/// Every digit of a number after its prefix, leading zeros included through
+/// `len`. Numbers in national form only gain the country code once resolved,
+/// so changing the country code does not need the phonebook again.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+struct Key {
+    digits: u64,
+    len: u8,
+    form: Form,
+}
+
+impl Key {
+    fn parse(number: &str) -> Option<Self> {
+        let plus = number.trim_start().starts_with('+');
+        let digits: String = number.chars().filter(char::is_ascii_digit).collect();
+
+        let (digits, form) = if plus {
+            (digits.as_str(), Form::International)
+        } else if let Some(international) = digits.strip_prefix(INTERNATIONAL_PREFIX) {
+            (international, Form::International)
+        } else if let Some(national) = digits.strip_prefix(TRUNK_PREFIX) {
+            (national, Form::National)
+        } else {
+            (digits.as_str(), Form::Local)
+        };
+
+        if digits.is_empty() || digits.len() > MAX_DIGITS {
+            return None;
+        }
+
+        Some(Self {
+            digits: digits.parse().ok()?,
+            len: digits.len() as u8,
+            form,
+        })
+    }
+
+    fn resolve(self, country_code: u16) -> Number {
+        if self.form != Form::National {
+            return Number {
+                digits: self.digits,
+                len: self.len,
+                complete: self.form == Form::International,
+            };
+        }
+
+        Number {
+            digits: country_code as u64 * 10u64.pow(self.len as u32) + self.digits,
+            len: self.len + country_code.checked_ilog10().map_or(1, |log| log + 1) as u8,
+            complete: true,
+        }
+    }
+}
+
+/// A number with its country code where known.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+struct Number {
+    digits: u64,
+    len: u8,
+    complete: bool,
+}
+
+impl Number {
+    /// Complete numbers match on every digit, otherwise on the digits both
+    /// have as long as there are enough of them.
+    fn matches(&self, other: &Number) -> bool {
+        if self.complete && other.complete {
+            return self.digits == other.digits && self.len == other.len;
+        }
+
+        let len = self.len.min(other.len);
+        let modulus = 10u64.pow(len as u32);
+
+        len >= MIN_MATCH_DIGITS && self.digits % modulus == other.digits % modulus
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Entry {
+    key: Key,
+    /// `key` resolved with the index's country code.
+    number: Number,
+    name: [u8; CONTACT_NAME_LEN],
+    name_len: u8,
+}
+
+impl Entry {
+    const EMPTY: Self = Self {
+        key: Key {
+            digits: 0,
+            len: 0,
+            form: Form::Local,
+        },
+        number: Number {
+            digits: 0,
+            len: 0,
+            complete: false,
+        },
+        name: [0; CONTACT_NAME_LEN],
+        name_len: 0,
+    };
+
+    fn name(&self) -> &str {
+        str::from_utf8(&self.name[..self.name_len as usize]).unwrap_or_default()
+    }
+}
+
+pub struct ContactIndex {
+    entries: [Entry; CONTACT_INDEX_CAPACITY],
+    len: usize,
+    country_code: u16,
+}
+
+impl Default for ContactIndex {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ContactIndex {
+    pub const fn new() -> Self {
+        Self {
+            entries: [Entry::EMPTY; CONTACT_INDEX_CAPACITY],
+            len: 0,
+            country_code: DEFAULT_COUNTRY_CODE,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    /// Resolves the numbers in national form again with `country_code`.
+    pub fn set_country_code(&mut self, country_code: u16) {
+        self.country_code = country_code;
+
+        for entry in &mut self.entries[..self.len] {
+            entry.number = entry.key.resolve(country_code);
+        }
+    }
+
+    /// Adds every number of the contact, returns `false` once the index is
+    /// full. Numbers that are already present keep their first name.
+    pub fn insert(&mut self, contact: &Contact) -> bool {
+        let mut name = [0; CONTACT_NAME_LEN];
+        let mut name_len = 0;
+
+        for c in contact.name.chars() {
+            let len = c.len_utf8();
+
+            if name_len + len > CONTACT_NAME_LEN {
+                break;
+            }
+
+            c.encode_utf8(&mut name[name_len..]);
+            name_len += len;
+        }
+
+        for number in &contact.numbers {
+            let Some(key) = Key::parse(&number.number) else {
+                continue;
+            };
+            let number = key.resolve(self.country_code);
+
+            if self.find(&number).is_some() {
+                continue;
+            }
+
+            if self.len == CONTACT_INDEX_CAPACITY {
+                return false;
+            }
+
+            self.entries[self.len] = Entry {
+                key,
+                number,
+                name,
+                name_len: name_len as u8,
+            };
+            self.len += 1;
+        }
+
+        true
+    }
+
+    pub fn lookup(&self, number: &str) -> Option<String> {
+        self.find(&Key::parse(number)?.resolve(self.country_code))
+            .map(|entry| entry.name().to_string())
+    }
+
+    fn find(&self, number: &Number) -> Option<&Entry> {
+        self.entries[..self.len]
+            .iter()
+            .find(|entry| entry.number.matches(number))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::phonebook::vcard::{NumberKind, PhoneNumber};
+    use alloc::vec;
+
+    fn contact(name: &str, number: &str) -> Contact {
+        Contact {
+            name: name.to_string(),
+            numbers: vec![PhoneNumber {
+                kind: NumberKind::Cell,
+                number: number.to_string(),
+            }],
+        }
+    }
+
+    #[test]
+    fn national_and_international_forms_match() {
+        let mut contact_index = ContactIndex::new();
+        contact_index.insert(&contact("Ada Lovelace", "0412 345 678"));
+
+        for number in ["0412345678", "+61 412 345 678", "0061412345678"] {
+            assert_eq!(
+                contact_index.lookup(number).as_deref(),
+                Some("Ada Lovelace"),
+                "{}",
+                number
+            );
+        }
+    }
+
+    #[test]
+    fn numbers_match_on_every_digit() {
+        let mut contact_index = ContactIndex::new();
+        contact_index.insert(&contact("Ada Lovelace", "+61412345678"));
+
+        assert_eq!(contact_index.lookup("+44412345678"), None);
+        assert_eq!(contact_index.lookup("+6141234567"), None);
+    }
+
+    #[test]
+    fn numbers_without_a_prefix_match_on_trailing_digits() {
+        let mut contact_index = ContactIndex::new();
+        contact_index.insert(&contact("Ada Lovelace", "+61412345678"));
+        contact_index.insert(&contact("Grace Hopper", "5550100"));
+
+        assert_eq!(
+            contact_index.lookup("412345678").as_deref(),
+            Some("Ada Lovelace")
+        );
+        assert_eq!(
+            contact_index.lookup("+1 212 555 0100").as_deref(),
+            Some("Grace Hopper")
+        );
+        assert_eq!(contact_index.lookup("345678"), None);
+        assert_eq!(contact_index.lookup("412345679"), None);
+    }
+
+    #[test]
+    fn national_numbers_follow_the_country_code() {
+        let mut contact_index = ContactIndex::new();
+        contact_index.insert(&contact("Alan Turing", "020 7946 0000"));
+        contact_index.set_country_code(44);
+
+        assert_eq!(
+            contact_index.lookup("+44 20 7946 0000").as_deref(),
+            Some("Alan Turing")
+        );
+        assert_eq!(contact_index.lookup("+61 20 7946 0000"), None);
+    }
+}
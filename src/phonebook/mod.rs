@@ -0,0 +1,2 @@
+pub mod contact_index;
+pub mod vcard;
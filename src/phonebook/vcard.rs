@@ -0,0 +1,518 @@
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::str;
+use defmt::Format;
+
+pub const MAX_LINE_LEN: usize = 160;
+pub const MAX_NAME_LEN: usize = 48;
+pub const MAX_NUMBER_LEN: usize = 24;
+pub const MAX_NUMBERS: usize = 4;
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Format)]
+pub enum NumberKind {
+    Cell,
+    Home,
+    Work,
+    Other,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Format)]
+pub struct PhoneNumber {
+    pub kind: NumberKind,
+    pub number: String,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Default, Format)]
+pub struct Contact {
+    pub name: String,
+    pub numbers: Vec<PhoneNumber>,
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+enum Line {
+    Empty,
+    Pending,
+    Skipping,
+}
+
+/// Streaming vCard 2.1/3.0 parser, fed one line at a time as the module
+/// delivers them. Only `FN`, `N` and `TEL` are kept and every field is
+/// truncated, so the heap cost is bounded by a single contact regardless of
+/// the size of the phonebook or of properties such as `PHOTO`.
+pub struct VCardParser {
+    contact: Option<Contact>,
+    has_formatted_name: bool,
+    line: Vec<u8>,
+    state: Line,
+}
+
+impl Default for VCardParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VCardParser {
+    pub const fn new() -> Self {
+        Self {
+            contact: None,
+            has_formatted_name: false,
+            line: Vec::new(),
+            state: Line::Empty,
+        }
+    }
+
+    pub fn push_line(&mut self, line: &[u8]) -> Option<Contact> {
+        // vCard 3.0 folds long lines by starting the continuation with
+        // whitespace, vCard 2.1 quoted-printable values end with a soft `=`.
+        if let Some(continuation) = line.strip_prefix(b" ").or(line.strip_prefix(b"\t")) {
+            self.continue_line(continuation);
+            return None;
+        }
+
+        if self.state == Line::Pending && self.is_soft_line_break() {
+            self.line.pop();
+            self.continue_line(line);
+            return None;
+        }
+
+        let contact = self.flush();
+
+        let (name, _) = split_property(line);
+
+        if name.eq_ignore_ascii_case(b"BEGIN") || name.eq_ignore_ascii_case(b"END") {
+            return self.parse_line(line).or(contact);
+        }
+
+        if is_wanted(name) {
+            self.line.clear();
+            self.state = Line::Pending;
+            self.continue_line(line);
+        } else {
+            self.state = Line::Skipping;
+        }
+
+        contact
+    }
+
+    /// Ends the download, returning the last contact if the download
+    /// stopped before its `END`. The parser is then ready for the next one.
+    pub fn finish(&mut self) -> Option<Contact> {
+        self.flush();
+        self.contact
+            .take()
+            .filter(|contact| !contact.name.is_empty() || !contact.numbers.is_empty())
+    }
+
+    fn continue_line(&mut self, line: &[u8]) {
+        if self.state != Line::Pending {
+            return;
+        }
+
+        let len = line.len().min(MAX_LINE_LEN.saturating_sub(self.line.len()));
+        self.line.extend_from_slice(&line[..len]);
+    }
+
+    fn is_soft_line_break(&self) -> bool {
+        let (_, params) = split_property(&self.line);
+
+        self.line.last() == Some(&b'=') && is_quoted_printable(params)
+    }
+
+    fn flush(&mut self) -> Option<Contact> {
+        let state = core::mem::replace(&mut self.state, Line::Empty);
+
+        if state != Line::Pending {
+            return None;
+        }
+
+        let line = core::mem::take(&mut self.line);
+        self.parse_line(&line)
+    }
+
+    fn parse_line(&mut self, line: &[u8]) -> Option<Contact> {
+        let separator = line.iter().position(|c| c == &b':')?;
+
+        let (name, params) = split_property(&line[..separator]);
+        let value = &line[separator + 1..];
+
+        if name.eq_ignore_ascii_case(b"BEGIN") {
+            self.contact = Some(Contact::default());
+            self.has_formatted_name = false;
+            return None;
+        }
+
+        if name.eq_ignore_ascii_case(b"END") {
+            return self
+                .contact
+                .take()
+                .filter(|contact| !contact.name.is_empty() || !contact.numbers.is_empty());
+        }
+
+        let contact = self.contact.as_mut()?;
+
+        let value = decode_value(params, value);
+
+        if name.eq_ignore_ascii_case(b"FN") {
+            let name = unescape(&value);
+
+            if !name.is_empty() {
+                contact.name = truncate(name.trim(), MAX_NAME_LEN);
+                self.has_formatted_name = true;
+            }
+        } else if name.eq_ignore_ascii_case(b"N") {
+            if !self.has_formatted_name {
+                contact.name = truncate(structured_name(&value).trim(), MAX_NAME_LEN);
+            }
+        } else if name.eq_ignore_ascii_case(b"TEL") {
+            let number: String = value
+                .chars()
+                .filter(|c| c.is_ascii_digit() || matches!(c, '+' | '*' | '#'))
+                .take(MAX_NUMBER_LEN)
+                .collect();
+
+            if !number.is_empty() && contact.numbers.len() < MAX_NUMBERS {
+                contact.numbers.push(PhoneNumber {
+                    kind: number_kind(params),
+                    number,
+                });
+            }
+        }
+
+        None
+    }
+}
+
+/// Splits `group.NAME;PARAM=A;PARAM=B` into the bare property name and its
+/// parameters, dropping the optional group prefix.
+fn split_property(line: &[u8]) -> (&[u8], &[u8]) {
+    let property = match line.iter().position(|c| c == &b':') {
+        Some(index) => &line[..index],
+        None => line,
+    };
+
+    let (name, params) = match property.iter().position(|c| c == &b';') {
+        Some(index) => (&property[..index], &property[index + 1..]),
+        None => (property, &[] as &[u8]),
+    };
+
+    let name = match name.iter().rposition(|c| c == &b'.') {
+        Some(index) => &name[index + 1..],
+        None => name,
+    };
+
+    (name.trim_ascii(), params)
+}
+
+fn is_wanted(name: &[u8]) -> bool {
+    [b"FN" as &[u8], b"N", b"TEL"]
+        .iter()
+        .any(|wanted| name.eq_ignore_ascii_case(wanted))
+}
+
+fn params(params: &[u8]) -> impl Iterator<Item = &[u8]> {
+    params.split(|c| c == &b';' || c == &b',').map(|param| {
+        match param.iter().position(|c| c == &b'=') {
+            Some(index) => &param[index + 1..],
+            None => param,
+        }
+    })
+}
+
+fn is_quoted_printable(params: &[u8]) -> bool {
+    self::params(params).any(|param| param.eq_ignore_ascii_case(b"QUOTED-PRINTABLE"))
+}
+
+fn number_kind(params: &[u8]) -> NumberKind {
+    for param in self::params(params) {
+        if param.eq_ignore_ascii_case(b"CELL") {
+            return NumberKind::Cell;
+        }
+        if param.eq_ignore_ascii_case(b"HOME") {
+            return NumberKind::Home;
+        }
+        if param.eq_ignore_ascii_case(b"WORK") {
+            return NumberKind::Work;
+        }
+    }
+
+    NumberKind::Other
+}
+
+fn decode_value(params: &[u8], value: &[u8]) -> String {
+    if !is_quoted_printable(params) {
+        return String::from_utf8_lossy(value).to_string();
+    }
+
+    let mut decoded = Vec::with_capacity(value.len());
+    let mut bytes = value.iter();
+
+    while let Some(&byte) = bytes.next() {
+        if byte != b'=' {
+            decoded.push(byte);
+            continue;
+        }
+
+        let hex = bytes
+            .as_slice()
+            .get(..2)
+            .and_then(|hex| str::from_utf8(hex).ok());
+
+        match hex.and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+            Some(byte) => {
+                decoded.push(byte);
+                bytes.nth(1);
+            }
+            None => decoded.push(byte),
+        }
+    }
+
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+fn unescape(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('n') | Some('N') => unescaped.push(' '),
+                Some(c) => unescaped.push(c),
+                None => {}
+            },
+            c => unescaped.push(c),
+        }
+    }
+
+    unescaped
+}
+
+/// Turns `Family;Given;Middle;Prefix;Suffix` into "Prefix Given Middle Family
+/// Suffix", skipping empty components.
+fn structured_name(value: &str) -> String {
+    let mut components = [""; 5];
+    let mut start = 0;
+    let mut index = 0;
+    let mut escaped = false;
+
+    for (offset, c) in value.char_indices() {
+        match c {
+            '\\' if !escaped => {
+                escaped = true;
+                continue;
+            }
+            ';' if !escaped && index < components.len() - 1 => {
+                components[index] = &value[start..offset];
+                start = offset + 1;
+                index += 1;
+            }
+            _ => {}
+        }
+        escaped = false;
+    }
+    components[index] = &value[start..];
+
+    let [family, given, middle, prefix, suffix] = components;
+    let mut name = String::new();
+
+    for component in [prefix, given, middle, family, suffix] {
+        let component = unescape(component.trim());
+
+        if !component.is_empty() {
+            if !name.is_empty() {
+                name.push(' ');
+            }
+            name.push_str(&component);
+        }
+    }
+
+    name
+}
+
+fn truncate(value: &str, max_len: usize) -> String {
+    match value.char_indices().nth(max_len) {
+        Some((index, _)) => value[..index].to_string(),
+        None => value.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    fn parse(vcards: &str) -> Vec<Contact> {
+        let mut parser = VCardParser::new();
+        let mut contacts: Vec<Contact> = vcards
+            .lines()
+            .filter_map(|line| parser.push_line(line.as_bytes()))
+            .collect();
+        contacts.extend(parser.finish());
+        contacts
+    }
+
+    fn number(kind: NumberKind, number: &str) -> PhoneNumber {
+        PhoneNumber {
+            kind,
+            number: number.to_string(),
+        }
+    }
+
+    /// As exported by the Android contacts app, vCard 2.1 with non-ASCII
+    /// names quoted-printable encoded and split with soft line breaks.
+    const ANDROID: &str = "\
+BEGIN:VCARD
+VERSION:2.1
+N;CHARSET=UTF-8;ENCODING=QUOTED-PRINTABLE:=C3=85ngstr=C3=B6m-Lindqvist;Zo=C3=AB;;;
+FN;CHARSET=UTF-8;ENCODING=QUOTED-PRINTABLE:Zo=C3=AB =C3=85ngstr=C3=B6m-Lindqvist=
+=20=E2=80=93 Caf=C3=A9 =C3=A0 la cr=C3=A8me
+TEL;CELL:+61 412 345 678
+TEL;HOME:(02) 9876 5432
+TEL;WORK;PREF:02 8765 4321
+EMAIL;HOME:zoe@example.com
+END:VCARD
+BEGIN:VCARD
+VERSION:2.1
+N:Nguyen;Bao;;;
+TEL;CELL:0400 111 222
+TEL;CELL:0400 333 444
+TEL;HOME:02 9111 2222
+TEL;WORK:02 9333 4444
+TEL;X-CUSTOM(CHARSET=UTF-8,ENCODING=QUOTED-PRINTABLE,Boat):02 9555 6666
+END:VCARD
+";
+
+    /// As shared from iOS Contacts, vCard 3.0 with grouped properties, type
+    /// parameters and long values folded onto lines starting with a space.
+    const IOS: &str = "\
+BEGIN:VCARD
+VERSION:3.0
+PRODID:-//Apple Inc.//iPhone OS 17.4//EN
+N:Appleseed;Johnny;;Dr;
+FN:Dr Johnny Appleseed
+ORG:Apple Inc.;
+item1.TEL;type=pref:+61 2 9999 0000
+item1.X-ABLabel:_$!<Main>!$_
+TEL;type=CELL;type=VOICE:0412 000 111
+TEL;type=HOME;type=VOICE:(02) 9000 1111
+NOTE:Met at the conference\\, remember to follow up about the proposal that
+  was sent in March.
+PHOTO;ENCODING=b;TYPE=JPEG:/9j/4AAQSkZJRgABAQAASABIAAD/4QBMRXhpZgAATU0AKgAAAA
+ gAAYdpAAQAAAABAAAAGgAAAAAAA6ABAAMAAAABAAEAAKACAAQAAAABAAAAMKADAAQAAAABAAAAMA
+END:VCARD
+BEGIN:VCARD
+VERSION:3.0
+N:Konstantinopoulou-Van Der Westhuizen;Maximiliane;Alexandra;;
+FN:Maximiliane Alexandra Konstantinopoulou-Van Der Westhuizen of Cape Town
+ and Thessaloniki
+TEL;type=WORK;type=VOICE:+27 21 555 0100 ext 12345 67890 123
+END:VCARD
+";
+
+    #[test]
+    fn android_quoted_printable_soft_line_breaks() {
+        let contacts = parse(ANDROID);
+
+        assert_eq!(contacts.len(), 2);
+        assert_eq!(contacts[0].name, "Zoë Ångström-Lindqvist – Café à la crème");
+        assert_eq!(
+            contacts[0].numbers,
+            [
+                number(NumberKind::Cell, "+61412345678"),
+                number(NumberKind::Home, "0298765432"),
+                number(NumberKind::Work, "0287654321"),
+            ]
+        );
+    }
+
+    #[test]
+    fn name_falls_back_to_structured_name() {
+        let contacts = parse(ANDROID);
+
+        assert_eq!(contacts[1].name, "Bao Nguyen");
+    }
+
+    #[test]
+    fn numbers_beyond_the_limit_are_dropped() {
+        let contacts = parse(ANDROID);
+
+        assert_eq!(
+            contacts[1].numbers,
+            [
+                number(NumberKind::Cell, "0400111222"),
+                number(NumberKind::Cell, "0400333444"),
+                number(NumberKind::Home, "0291112222"),
+                number(NumberKind::Work, "0293334444"),
+            ]
+        );
+    }
+
+    #[test]
+    fn ios_folded_lines_and_grouped_properties() {
+        let contacts = parse(IOS);
+
+        assert_eq!(contacts.len(), 2);
+        assert_eq!(contacts[0].name, "Dr Johnny Appleseed");
+        assert_eq!(
+            contacts[0].numbers,
+            [
+                number(NumberKind::Other, "+61299990000"),
+                number(NumberKind::Cell, "0412000111"),
+                number(NumberKind::Home, "0290001111"),
+            ]
+        );
+    }
+
+    #[test]
+    fn over_long_fields_are_truncated() {
+        let contacts = parse(IOS);
+
+        assert_eq!(contacts[1].name.chars().count(), MAX_NAME_LEN);
+        assert!(contacts[1]
+            .name
+            .starts_with("Maximiliane Alexandra Konstantinopoulou-Van Der"));
+        assert_eq!(
+            contacts[1].numbers,
+            [number(NumberKind::Work, "+27215550100123456789012")]
+        );
+    }
+
+    #[test]
+    fn over_long_lines_are_truncated() {
+        let name = "Wolfeschlegelsteinhausenbergerdorff ".repeat(8);
+        let contacts = parse(&alloc::format!(
+            "BEGIN:VCARD\nVERSION:3.0\nN:{};Hubert;;;\nTEL:1\nEND:VCARD\n",
+            name
+        ));
+
+        assert_eq!(contacts.len(), 1);
+        assert_eq!(contacts[0].name.chars().count(), MAX_NAME_LEN);
+        assert_eq!(contacts[0].numbers, [number(NumberKind::Other, "1")]);
+    }
+
+    #[test]
+    fn finish_returns_a_contact_cut_short() {
+        let mut parser = VCardParser::new();
+
+        for line in [
+            "BEGIN:VCARD",
+            "VERSION:3.0",
+            "FN:Ada Lovelace",
+            "TEL:+44 20 7946 0000",
+        ] {
+            assert_eq!(parser.push_line(line.as_bytes()), None);
+        }
+
+        assert_eq!(
+            parser.finish(),
+            Some(Contact {
+                name: "Ada Lovelace".to_string(),
+                numbers: vec![number(NumberKind::Other, "+442079460000")],
+            })
+        );
+        assert_eq!(parser.finish(), None);
+    }
+}
@@ -1,10 +1,11 @@
 use alloc::format;
-use alloc::string::ToString;
 use display_interface_i2c::I2CInterface;
+use embassy_futures::select::{select, Either};
 use embassy_stm32::dma::NoDma;
 use embassy_stm32::i2c::{self, Config, I2c};
 use embassy_stm32::time::Hertz;
 use embassy_stm32::{bind_interrupts, peripherals};
+use embassy_time::{Duration, Instant, Timer};
 use embedded_graphics::mono_font::MonoTextStyle;
 use embedded_graphics::pixelcolor::BinaryColor;
 use embedded_graphics::primitives::{Line, PrimitiveStyle, Rectangle};
@@ -12,13 +13,16 @@ use embedded_graphics::{mono_font::ascii::FONT_6X10, prelude::*};
 use embedded_text::{alignment::HorizontalAlignment, TextBox};
 use ssd1306::{prelude::*, Ssd1306};
 
-use crate::app_state::app_state_get;
+use crate::app_state::app_state_select;
 
 bind_interrupts!(struct Irqs {
     I2C1_EV => i2c::EventInterruptHandler<peripherals::I2C1>;
     I2C1_ER => i2c::ErrorInterruptHandler<peripherals::I2C1>;
 });
 
+/// How often the progress bar is redrawn while the playback clock is running.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+
 #[embassy_executor::task]
 pub async fn piicodev_oled(
     peri: peripherals::I2C1,
@@ -53,17 +57,18 @@ pub async fn piicodev_oled(
 
     let character_style = MonoTextStyle::new(&FONT_6X10, BinaryColor::On);
 
-    loop {
-        let app_state = app_state_get().await;
+    let mut playback_selector = app_state_select(|app_state| app_state.playback.clone());
+    let mut playback = playback_selector.changed().await;
 
+    loop {
         display.clear(BinaryColor::Off).unwrap();
 
         TextBox::with_alignment(
             format!(
                 "{} - {} - {}",
-                app_state.song_title.unwrap_or("".to_string()),
-                app_state.song_album.unwrap_or("".to_string()),
-                app_state.song_artist.unwrap_or("".to_string())
+                playback.title.as_deref().unwrap_or(""),
+                playback.album.as_deref().unwrap_or(""),
+                playback.artist.as_deref().unwrap_or("")
             )
             .as_str(),
             Rectangle::new(Point::new(0, 0), Size::new(128, 48)),
@@ -75,10 +80,10 @@ pub async fn piicodev_oled(
 
         let mut progress = 0;
 
-        if let (Some(elapsed), Some(total)) = (
-            app_state.playback_elapsed_time,
-            app_state.playback_total_time,
-        ) {
+        let total = playback.clock.total();
+
+        if !total.is_zero() {
+            let elapsed = playback.clock.elapsed(Instant::now());
             let progress_ratio = elapsed.as_millis() as f32 / total.as_millis() as f32;
             let scaled_progress = progress_ratio * 118.0;
 
@@ -91,5 +96,16 @@ pub async fn piicodev_oled(
             .unwrap();
 
         display.flush().unwrap();
+
+        if !playback.clock.is_running() {
+            playback = playback_selector.changed().await;
+            continue;
+        }
+
+        if let Either::First(next) =
+            select(playback_selector.changed(), Timer::after(PROGRESS_INTERVAL)).await
+        {
+            playback = next;
+        }
     }
 }
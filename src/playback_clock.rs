@@ -0,0 +1,193 @@
+use core::time::Duration;
+use embassy_time::Instant;
+
+use crate::feasycom_protocol::indication::PlayStat;
+
+/// Approximate seek speed of the phone while fast forwarding or rewinding,
+/// the module does not report it so reports keep correcting the estimate.
+const SEEK_RATE: u32 = 4;
+
+/// Estimates the playback position between `+TRACKSTAT` reports by anchoring
+/// on the last reported position and advancing locally from there.
+#[derive(Debug, Copy, Clone)]
+pub struct PlaybackClock {
+    elapsed: Duration,
+    total: Duration,
+    play_stat: PlayStat,
+    anchor: Instant,
+}
+
+/// Clocks are equal when they report the same position, duration and play
+/// status, so re-anchoring on an unchanged report is not a change.
+impl PartialEq for PlaybackClock {
+    fn eq(&self, other: &Self) -> bool {
+        self.elapsed == other.elapsed
+            && self.total == other.total
+            && self.play_stat == other.play_stat
+    }
+}
+
+impl Eq for PlaybackClock {}
+
+impl Default for PlaybackClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PlaybackClock {
+    pub const fn new() -> Self {
+        Self {
+            elapsed: Duration::ZERO,
+            total: Duration::ZERO,
+            play_stat: PlayStat::Stopped,
+            anchor: Instant::from_ticks(0),
+        }
+    }
+
+    pub fn play_stat(&self) -> PlayStat {
+        self.play_stat
+    }
+
+    /// Whether the position moves without new reports, i.e. whether a UI
+    /// needs to keep redrawing.
+    pub fn is_running(&self) -> bool {
+        self.play_stat != PlayStat::Stopped && self.play_stat != PlayStat::Paused
+    }
+
+    /// Re-anchors on a reported position, discarding any drift accumulated
+    /// by the local estimate.
+    pub fn anchor(
+        &mut self,
+        elapsed: Duration,
+        total: Duration,
+        play_stat: PlayStat,
+        now: Instant,
+    ) {
+        self.elapsed = elapsed;
+        self.total = total;
+        self.play_stat = play_stat;
+        self.anchor = now;
+    }
+
+    /// Changes the play status without a new position, freezing or starting
+    /// the clock from wherever the estimate currently is.
+    pub fn set_play_stat(&mut self, play_stat: PlayStat, now: Instant) {
+        if play_stat == self.play_stat {
+            return;
+        }
+
+        let elapsed = match play_stat {
+            PlayStat::Stopped => Duration::ZERO,
+            _ => self.elapsed(now),
+        };
+
+        self.anchor(elapsed, self.total, play_stat, now);
+    }
+
+    pub fn elapsed(&self, now: Instant) -> Duration {
+        let since = Duration::from_micros(now.saturating_duration_since(self.anchor).as_micros());
+
+        let elapsed = match self.play_stat {
+            PlayStat::Stopped | PlayStat::Paused => self.elapsed,
+            PlayStat::Playing => self.elapsed.saturating_add(since),
+            PlayStat::FastForwarding => self.elapsed.saturating_add(since * SEEK_RATE),
+            PlayStat::FastRewinding => self.elapsed.saturating_sub(since * SEEK_RATE),
+        };
+
+        // Some phones report a total of zero for streams without a known length.
+        if self.total.is_zero() {
+            elapsed
+        } else {
+            elapsed.min(self.total)
+        }
+    }
+
+    pub fn total(&self) -> Duration {
+        self.total
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn extrapolates_from_the_last_report() {
+        #[rustfmt::skip]
+        let cases: &[(&str, PlayStat, u64, u64, u64)] = &[
+            // (name, play status, reported, total, expected 10 s later)
+            ("playing", PlayStat::Playing, 30, 200, 40),
+            ("paused", PlayStat::Paused, 30, 200, 30),
+            ("stopped", PlayStat::Stopped, 30, 200, 30),
+            ("fast forwarding", PlayStat::FastForwarding, 30, 200, 70),
+            ("fast rewinding", PlayStat::FastRewinding, 30, 200, 0),
+            ("clamped at the length", PlayStat::Playing, 195, 200, 200),
+            ("unknown length", PlayStat::Playing, 195, 0, 205),
+        ];
+
+        for (name, play_stat, reported, total, expected) in cases {
+            let mut clock = PlaybackClock::new();
+            clock.anchor(
+                secs(*reported),
+                secs(*total),
+                *play_stat,
+                Instant::from_secs(5),
+            );
+
+            assert_eq!(
+                clock.elapsed(Instant::from_secs(15)),
+                secs(*expected),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn play_status_changes_keep_the_position() {
+        let mut clock = PlaybackClock::new();
+        clock.anchor(
+            secs(30),
+            secs(200),
+            PlayStat::Playing,
+            Instant::from_secs(0),
+        );
+
+        clock.set_play_stat(PlayStat::Paused, Instant::from_secs(10));
+        assert_eq!(clock.elapsed(Instant::from_secs(60)), secs(40));
+        assert!(!clock.is_running());
+
+        clock.set_play_stat(PlayStat::Playing, Instant::from_secs(60));
+        assert_eq!(clock.elapsed(Instant::from_secs(65)), secs(45));
+        assert!(clock.is_running());
+
+        clock.set_play_stat(PlayStat::Stopped, Instant::from_secs(70));
+        assert_eq!(clock.elapsed(Instant::from_secs(80)), Duration::ZERO);
+    }
+
+    #[test]
+    fn reanchoring_on_the_same_report_is_no_change() {
+        let mut clock = PlaybackClock::new();
+        clock.anchor(
+            secs(30),
+            secs(200),
+            PlayStat::Playing,
+            Instant::from_secs(0),
+        );
+
+        let mut reanchored = clock;
+        reanchored.anchor(
+            secs(30),
+            secs(200),
+            PlayStat::Playing,
+            Instant::from_secs(10),
+        );
+
+        assert_eq!(clock, reanchored);
+    }
+}
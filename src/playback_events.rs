@@ -0,0 +1,380 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::time::Duration;
+use defmt::Format;
+use embassy_sync::pubsub::{self, PubSubChannel, Subscriber};
+use embassy_time::Instant;
+
+use crate::{
+    feasycom_protocol::indication::{A2dpStat, AvrcpStat, Indication, PlayStat},
+    playback_clock::PlaybackClock,
+    TaskRawMutex,
+};
+
+/// A reported position further than this from the local estimate is a seek
+/// rather than drift.
+const SEEK_THRESHOLD: Duration = Duration::from_secs(3);
+
+/// A jump back to the start from within this much of the end, without new
+/// track info, is the same track playing again, e.g. repeat one.
+const REPEAT_MARGIN: Duration = Duration::from_secs(5);
+
+const EVENT_CAPACITY: usize = 8;
+const EVENT_SUBSCRIBERS: usize = 4;
+
+static PLAYBACK_EVENT_CHANNEL: PubSubChannel<
+    TaskRawMutex,
+    PlaybackEvent,
+    EVENT_CAPACITY,
+    EVENT_SUBSCRIBERS,
+    1,
+> = PubSubChannel::new();
+
+pub type PlaybackEventSubscriber =
+    Subscriber<'static, TaskRawMutex, PlaybackEvent, EVENT_CAPACITY, EVENT_SUBSCRIBERS, 1>;
+
+pub fn playback_event_publish(event: PlaybackEvent) {
+    PLAYBACK_EVENT_CHANNEL
+        .immediate_publisher()
+        .publish_immediate(event);
+}
+
+pub fn playback_events() -> Result<PlaybackEventSubscriber, pubsub::Error> {
+    PLAYBACK_EVENT_CHANNEL.subscriber()
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Format)]
+pub struct Track {
+    pub title: String,
+    pub artist: String,
+    pub album: String,
+    /// Zero until the first `+TRACKSTAT` for the track reports it.
+    pub length: Duration,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Format)]
+pub enum PlaybackEvent {
+    TrackStarted(Track),
+    /// `played` is the time actually spent playing, excluding pauses and
+    /// skipped over parts.
+    TrackFinished {
+        track: Track,
+        played: Duration,
+    },
+    Seeked {
+        from: Duration,
+        to: Duration,
+    },
+    Paused,
+    Resumed,
+    Stopped,
+}
+
+/// Derives playback events from successive track indications.
+pub struct PlaybackTracker {
+    track: Option<Track>,
+    clock: PlaybackClock,
+    anchored: bool,
+    played: Duration,
+    playing_since: Option<Instant>,
+    /// Position when fast forwarding or rewinding started. The position jumps
+    /// while scanning, so it is one seek from here once playback carries on.
+    scan_from: Option<Duration>,
+}
+
+impl Default for PlaybackTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PlaybackTracker {
+    pub const fn new() -> Self {
+        Self {
+            track: None,
+            clock: PlaybackClock::new(),
+            anchored: false,
+            played: Duration::ZERO,
+            playing_since: None,
+            scan_from: None,
+        }
+    }
+
+    pub fn next(&mut self, indication: &Indication, now: Instant) -> Vec<PlaybackEvent> {
+        let mut events = Vec::new();
+
+        match indication {
+            Indication::TrackInfo(track_info) => {
+                let changed = match &self.track {
+                    Some(track) => {
+                        track.title != track_info.title
+                            || track.artist != track_info.artist
+                            || track.album != track_info.album
+                    }
+                    None => true,
+                };
+
+                if changed && !track_info.title.is_empty() {
+                    self.finish(now, &mut events);
+                    self.start(
+                        Track {
+                            title: track_info.title.clone(),
+                            artist: track_info.artist.clone(),
+                            album: track_info.album.clone(),
+                            length: Duration::ZERO,
+                        },
+                        now,
+                        &mut events,
+                    );
+                }
+            }
+            Indication::TrackStat(track_stat) => {
+                let estimate = self.clock.elapsed(now);
+                let reported = track_stat.elapsed_time;
+
+                if let Some(track) = &mut self.track {
+                    track.length = track_stat.total_time;
+                }
+
+                let scanning = is_scanning(track_stat.play_stat);
+
+                let repeated = self.anchored
+                    && !scanning
+                    && !is_scanning(self.clock.play_stat())
+                    && self.scan_from.is_none()
+                    && !track_stat.total_time.is_zero()
+                    && estimate + REPEAT_MARGIN >= track_stat.total_time
+                    && reported < REPEAT_MARGIN;
+
+                if repeated {
+                    if let Some(track) = self.track.clone() {
+                        self.finish(now, &mut events);
+                        self.start(track, now, &mut events);
+                    }
+                } else if self.anchored && !scanning {
+                    let from = self.scan_from.take().unwrap_or(estimate);
+
+                    if abs_diff(from, reported) > SEEK_THRESHOLD {
+                        events.push(PlaybackEvent::Seeked { from, to: reported });
+                    }
+                }
+
+                self.set_play_stat(track_stat.play_stat, now, &mut events);
+                self.clock
+                    .anchor(reported, track_stat.total_time, track_stat.play_stat, now);
+                self.anchored = true;
+            }
+            Indication::PlayStat(play_stat) => {
+                self.set_play_stat(*play_stat, now, &mut events);
+                self.clock.set_play_stat(*play_stat, now);
+            }
+            Indication::AvrcpStat(avrcp_stat) if *avrcp_stat != AvrcpStat::Connected => {
+                self.disconnect(now, &mut events)
+            }
+            Indication::A2dpStat(a2dp_stat)
+                if !matches!(a2dp_stat, A2dpStat::Connected | A2dpStat::Streaming) =>
+            {
+                self.disconnect(now, &mut events)
+            }
+            _ => {}
+        }
+
+        events
+    }
+
+    fn start(&mut self, track: Track, now: Instant, events: &mut Vec<PlaybackEvent>) {
+        self.played = Duration::ZERO;
+        self.anchored = false;
+        self.scan_from = None;
+
+        if self.playing_since.is_some() {
+            self.playing_since = Some(now);
+        }
+
+        events.push(PlaybackEvent::TrackStarted(track.clone()));
+        self.track = Some(track);
+    }
+
+    fn finish(&mut self, now: Instant, events: &mut Vec<PlaybackEvent>) {
+        self.accumulate(now);
+
+        if let Some(track) = self.track.take() {
+            events.push(PlaybackEvent::TrackFinished {
+                track,
+                played: self.played,
+            });
+        }
+    }
+
+    fn disconnect(&mut self, now: Instant, events: &mut Vec<PlaybackEvent>) {
+        if self.track.is_none() && self.playing_since.is_none() {
+            return;
+        }
+
+        self.finish(now, events);
+        self.playing_since = None;
+        self.anchored = false;
+        self.scan_from = None;
+        self.clock = PlaybackClock::new();
+
+        events.push(PlaybackEvent::Stopped);
+    }
+
+    fn set_play_stat(
+        &mut self,
+        play_stat: PlayStat,
+        now: Instant,
+        events: &mut Vec<PlaybackEvent>,
+    ) {
+        let previous = self.clock.play_stat();
+
+        if play_stat == previous {
+            return;
+        }
+
+        self.accumulate(now);
+        self.playing_since = (play_stat == PlayStat::Playing).then_some(now);
+
+        if is_scanning(play_stat) && !is_scanning(previous) && self.anchored {
+            self.scan_from = Some(self.clock.elapsed(now));
+        }
+
+        match play_stat {
+            PlayStat::Playing if previous == PlayStat::Paused => {
+                events.push(PlaybackEvent::Resumed)
+            }
+            PlayStat::Paused => events.push(PlaybackEvent::Paused),
+            PlayStat::Stopped => events.push(PlaybackEvent::Stopped),
+            _ => {}
+        }
+    }
+
+    fn accumulate(&mut self, now: Instant) {
+        if let Some(playing_since) = self.playing_since {
+            let since = now.saturating_duration_since(playing_since);
+            self.played += Duration::from_micros(since.as_micros());
+            self.playing_since = Some(now);
+        }
+    }
+}
+
+fn is_scanning(play_stat: PlayStat) -> bool {
+    matches!(
+        play_stat,
+        PlayStat::FastForwarding | PlayStat::FastRewinding
+    )
+}
+
+fn abs_diff(a: Duration, b: Duration) -> Duration {
+    a.checked_sub(b).unwrap_or_else(|| b - a)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(title: &str, length: u64) -> Track {
+        Track {
+            title: title.into(),
+            artist: "Artist".into(),
+            album: "Album".into(),
+            length: Duration::from_secs(length),
+        }
+    }
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    type Case<'a> = (&'a str, &'a [(u64, &'a [u8])], &'a [PlaybackEvent]);
+
+    #[test]
+    fn events() {
+        use PlaybackEvent::*;
+
+        let title_a: &[u8] = b"+TRACKINFO=A\xffArtist\xffAlbum";
+        let title_b: &[u8] = b"+TRACKINFO=B\xffArtist\xffAlbum";
+
+        #[rustfmt::skip]
+        let cases: &[Case] = &[
+            ("normal progress", &[
+                (0, title_a),
+                (0, b"+TRACKSTAT=1,0,200000"),
+                (10, b"+TRACKSTAT=1,10500,200000"),
+                (20, b"+TRACKSTAT=1,19000,200000"),
+                (40, title_b),
+            ], &[
+                TrackStarted(track("A", 0)),
+                TrackFinished { track: track("A", 200), played: secs(40) },
+                TrackStarted(track("B", 0)),
+            ]),
+            ("seek", &[
+                (0, title_a),
+                (0, b"+TRACKSTAT=1,0,200000"),
+                (10, b"+TRACKSTAT=1,60000,200000"),
+            ], &[
+                TrackStarted(track("A", 0)),
+                Seeked { from: secs(10), to: secs(60) },
+            ]),
+            ("fast forward is one seek", &[
+                (0, title_a),
+                (0, b"+TRACKSTAT=1,10000,200000"),
+                (2, b"+PLAYSTAT=3"),
+                (3, b"+TRACKSTAT=3,40000,200000"),
+                (4, b"+TRACKSTAT=3,90000,200000"),
+                (5, b"+TRACKSTAT=1,120000,200000"),
+                (6, b"+TRACKSTAT=1,121000,200000"),
+            ], &[
+                TrackStarted(track("A", 0)),
+                Seeked { from: secs(12), to: secs(120) },
+            ]),
+            ("rewinding to the start is not a repeat", &[
+                (0, title_a),
+                (0, b"+TRACKSTAT=1,195000,200000"),
+                (1, b"+TRACKSTAT=4,100000,200000"),
+                (2, b"+TRACKSTAT=4,1000,200000"),
+                (3, b"+PLAYSTAT=1"),
+                (4, b"+TRACKSTAT=1,1000,200000"),
+            ], &[
+                TrackStarted(track("A", 0)),
+                Seeked { from: secs(196), to: secs(1) },
+            ]),
+            ("repeat of the same track", &[
+                (0, title_a),
+                (0, b"+TRACKSTAT=1,190000,200000"),
+                (10, b"+TRACKSTAT=1,1000,200000"),
+            ], &[
+                TrackStarted(track("A", 0)),
+                TrackFinished { track: track("A", 200), played: secs(10) },
+                TrackStarted(track("A", 200)),
+            ]),
+            ("pause and resume", &[
+                (0, title_a),
+                (0, b"+TRACKSTAT=1,0,200000"),
+                (10, b"+PLAYSTAT=2"),
+                (70, b"+TRACKSTAT=2,10000,200000"),
+                (70, b"+PLAYSTAT=1"),
+                (80, b"+TRACKSTAT=1,20000,200000"),
+                (90, title_b),
+            ], &[
+                TrackStarted(track("A", 0)),
+                Paused,
+                Resumed,
+                TrackFinished { track: track("A", 200), played: secs(30) },
+                TrackStarted(track("B", 0)),
+            ]),
+        ];
+
+        for (name, steps, expected) in cases {
+            let mut playback_tracker = PlaybackTracker::new();
+            let mut events = Vec::new();
+
+            for (at, line) in *steps {
+                let indication = Indication::try_from(*line).unwrap();
+                events.extend(playback_tracker.next(&indication, Instant::from_secs(*at)));
+            }
+
+            assert_eq!(&events, expected, "{}", name);
+        }
+    }
+}
@@ -0,0 +1,181 @@
+//! Replay of captures exported by `uart_capture`, through the same line
+//! framing and `handle_line` as `feasycom_task`. The firmware replays a
+//! capture at the pace it was captured with the `uart-replay` feature, the
+//! tests replay the captures under `captures/` on the host.
+
+use defmt::{error, info};
+use embassy_time::Instant;
+
+use crate::{
+    feasycom_state::{FeasycomEvent, FeasycomState, FeasycomStateMachine},
+    line_framer::{LineFramer, LINE_CAPACITY},
+    line_handler::handle_line,
+    playback_events::PlaybackTracker,
+};
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct ReplayRecord<'a> {
+    pub timestamp: u32,
+    pub rx: bool,
+    pub hex: &'a str,
+}
+
+impl ReplayRecord<'_> {
+    pub fn bytes(&self) -> impl Iterator<Item = u8> + '_ {
+        self.hex
+            .as_bytes()
+            .chunks_exact(2)
+            .filter_map(|pair| u8::from_str_radix(core::str::from_utf8(pair).ok()?, 16).ok())
+    }
+}
+
+/// Parses the lines of a capture, skipping comments and blank lines. Only
+/// the last three fields of each line are read, so lines copied from the
+/// defmt log keep their log prefix.
+pub fn replay_records(capture: &str) -> impl Iterator<Item = ReplayRecord<'_>> {
+    capture
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let mut fields = line.rsplit(' ');
+            let hex = fields.next()?;
+            let rx = match fields.next()? {
+                "rx" => true,
+                "tx" => false,
+                _ => return None,
+            };
+            let timestamp = fields.next()?.parse().ok()?;
+
+            Some(ReplayRecord { timestamp, rx, hex })
+        })
+}
+
+/// The state kept by `feasycom_task` that a capture can be replayed into.
+/// Bytes sent to the module are only logged, as nothing answers them.
+pub struct Replay {
+    feasycom_state_machine: FeasycomStateMachine,
+    playback_tracker: PlaybackTracker,
+    framer: LineFramer<LINE_CAPACITY>,
+}
+
+impl Replay {
+    /// Starts from the module having just been reset.
+    pub fn new(now: Instant) -> Self {
+        let mut feasycom_state_machine = FeasycomStateMachine::new();
+        feasycom_state_machine.dispatch_at(FeasycomEvent::Reset, now);
+
+        Self {
+            feasycom_state_machine,
+            playback_tracker: PlaybackTracker::new(),
+            framer: LineFramer::new(),
+        }
+    }
+
+    pub fn state(&self) -> FeasycomState {
+        self.feasycom_state_machine.state()
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        self.feasycom_state_machine.deadline()
+    }
+
+    /// Times out the current state if its deadline passed before `now`.
+    pub fn advance(&mut self, now: Instant) {
+        if let Some(deadline) = self.deadline().filter(|deadline| *deadline < now) {
+            self.feasycom_state_machine
+                .dispatch_at(FeasycomEvent::Timeout, deadline);
+        }
+    }
+
+    pub fn push(&mut self, record: &ReplayRecord, now: Instant) {
+        if !record.rx {
+            info!("tx {=str}", record.hex);
+            return;
+        }
+
+        for byte in record.bytes() {
+            match self.framer.push(byte) {
+                Some(Ok(line)) => {
+                    handle_line(
+                        line,
+                        &mut self.feasycom_state_machine,
+                        &mut self.playback_tracker,
+                        now,
+                        |_, _| {},
+                    );
+                }
+                Some(Err(e)) => error!("{}", e),
+                None => {}
+            }
+        }
+    }
+}
+
+/// Replays a whole capture at once, taking its timestamps as the time.
+pub fn replay(capture: &str) -> Replay {
+    let mut records = replay_records(capture).peekable();
+    let first = records.peek().map_or(0, |record| record.timestamp);
+    let mut replay = Replay::new(Instant::from_millis(first as u64));
+
+    for record in records {
+        let now = Instant::from_millis(record.timestamp as u64);
+
+        replay.advance(now);
+        replay.push(&record, now);
+    }
+
+    replay
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        app_state::app_state_with,
+        feasycom_protocol::indication::{A2dpStat, AvrcpStat, PlayStat},
+        test_lock,
+    };
+
+    #[test]
+    fn records_keep_their_log_prefix() {
+        let records: alloc::vec::Vec<_> =
+            replay_records("# comment\n\n0 tx 41540d0a\nINFO  12 rx 4f4b0d0a\n").collect();
+
+        assert_eq!(
+            records,
+            [
+                ReplayRecord {
+                    timestamp: 0,
+                    rx: false,
+                    hex: "41540d0a"
+                },
+                ReplayRecord {
+                    timestamp: 12,
+                    rx: true,
+                    hex: "4f4b0d0a"
+                },
+            ]
+        );
+        assert!(records[1].bytes().eq(*b"OK\r\n"));
+    }
+
+    #[test]
+    fn a2dp_playback() {
+        let _lock = test_lock();
+
+        let replay = replay(include_str!("../captures/a2dp_playback.txt"));
+        assert_eq!(replay.state(), FeasycomState::Streaming);
+
+        let app_state = app_state_with(Clone::clone);
+        assert_eq!(app_state.module.state, FeasycomState::Streaming);
+        assert_eq!(app_state.module.error, None);
+        assert_eq!(app_state.device.a2dp_stat, A2dpStat::Streaming);
+        assert_eq!(app_state.device.avrcp_stat, AvrcpStat::Connected);
+        assert_eq!(app_state.device.name.as_deref(), Some("Pixel 8"));
+        assert_eq!(app_state.playback.title.as_deref(), Some("Blue Monday"));
+        assert_eq!(app_state.playback.artist.as_deref(), Some("New Order"));
+        assert_eq!(app_state.playback.clock.play_stat(), PlayStat::Playing);
+        assert_eq!(app_state.audio.volume, Some(10));
+    }
+}
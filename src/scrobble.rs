@@ -0,0 +1,532 @@
+//! Journal of finished plays, kept in flash until a phone or PC tool exports
+//! them for submission to a scrobbling service.
+//!
+//! Sending `SCROBBLES` over SPP exports every play not yet exported, oldest
+//! first, as one JSON object per line:
+//!
+//! ```text
+//! {"timestamp":1700000000,"title":"...","artist":"...","album":"...","played":212,"length":245}
+//! ```
+//!
+//! `timestamp` is when the play started in seconds since the Unix epoch, read
+//! from the RTC, or `null` if the RTC had not been set. `played` is the time
+//! actually spent listening and `length` the length of the track, both in
+//! seconds, `length` is 0 when the phone never reported it. The export ends
+//! with a `{"count":N}` line.
+//!
+//! Once the tool has stored the plays, sending `SCROBBLES CLEAR` marks those
+//! of the last export as exported and answers with the `{"count":N}` of plays
+//! still waiting. Plays are never erased before being exported, a full
+//! journal drops new plays instead.
+//!
+//! Sending `TIME <seconds since the Unix epoch>` sets the RTC, the tool
+//! should do so before the first export after the clock lost power.
+
+use alloc::string::{String, ToString};
+use core::{
+    cell::{Cell, RefCell},
+    fmt::Write,
+    str,
+    time::Duration,
+};
+use defmt::{error, info, warn};
+use embassy_futures::select::{select, Either};
+use embassy_stm32::{
+    flash::{Async, Error, Flash},
+    rtc::{DateTime, DayOfWeek, Rtc},
+};
+use embassy_sync::blocking_mutex::{raw::ThreadModeRawMutex, Mutex};
+use embassy_sync::pubsub::WaitResult;
+
+use crate::{
+    app_state::{app_state_select, AppState},
+    command_queue::CommandResult,
+    crc::crc16,
+    playback_events::{playback_events, PlaybackEvent, Track},
+    spp::{floor_char_boundary, spp_send},
+    storage::storage_lock,
+};
+
+/// Sector 6 of the STM32F411CE, excluded from `FLASH` in `memory.x`. Plays
+/// are appended as fixed size records, each ending in a CRC of the rest.
+/// Exports are recorded by appending a cursor record holding the offset up to
+/// which plays were exported. Once every play in the sector was, it is erased
+/// the next time the module link is idle and the journal starts over.
+const JOURNAL_OFFSET: u32 = 0x4_0000;
+const JOURNAL_SIZE: u32 = 0x2_0000;
+const JOURNAL_END: u32 = JOURNAL_OFFSET + JOURNAL_SIZE;
+const RECORD_SIZE: usize = 128;
+const RECORD_MAGIC: &[u8; 4] = b"SCR2";
+const CURSOR_MAGIC: &[u8; 4] = b"SCRX";
+const CRC_OFFSET: usize = RECORD_SIZE - 2;
+
+const TITLE_LEN: usize = 48;
+const ARTIST_LEN: usize = 32;
+const ALBUM_LEN: usize = 26;
+
+/// Plays shorter than this are skips rather than listens and not recorded.
+const MIN_PLAYED: Duration = Duration::from_secs(30);
+
+/// The RTC starts from 2000 when its backup domain loses power, any earlier
+/// time was never set.
+const MIN_VALID_YEAR: u16 = 2024;
+
+static RTC: Mutex<ThreadModeRawMutex, RefCell<Option<Rtc>>> = Mutex::new(RefCell::new(None));
+
+/// Offset after the last play sent by `SCROBBLES`, until `SCROBBLES CLEAR`.
+static EXPORT_END: Mutex<ThreadModeRawMutex, Cell<Option<u32>>> = Mutex::new(Cell::new(None));
+
+pub fn scrobble_init(rtc: Rtc) {
+    RTC.lock(|cell| cell.replace(Some(rtc)));
+}
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Scrobble {
+    pub timestamp: Option<u32>,
+    pub title: String,
+    pub artist: String,
+    pub album: String,
+    pub played: u32,
+    pub length: u32,
+}
+
+/// Records every finished play.
+#[embassy_executor::task]
+pub async fn scrobble_task() -> ! {
+    let mut playback_events = playback_events().unwrap();
+    let mut link_idle = app_state_select(AppState::link_idle);
+
+    if link_idle.get() {
+        scrobble_erase().await;
+    }
+
+    loop {
+        match select(playback_events.next_message(), link_idle.changed()).await {
+            Either::First(WaitResult::Message(PlaybackEvent::TrackFinished { track, played })) => {
+                scrobble_record(&track, played).await
+            }
+            Either::First(WaitResult::Message(_)) => {}
+            Either::First(WaitResult::Lagged(count)) => {
+                warn!("missed {} playback events", count)
+            }
+            Either::Second(true) => scrobble_erase().await,
+            Either::Second(false) => {}
+        }
+    }
+}
+
+/// Erases the journal if every play in it was exported, called while the
+/// module link is idle as the erase stalls everything.
+async fn scrobble_erase() {
+    let mut flash = storage_lock().await;
+
+    let Some(flash) = flash.as_mut() else {
+        return;
+    };
+
+    let result = match scan(flash) {
+        Ok(journal) if journal.end == JOURNAL_OFFSET => Ok(()),
+        Ok(journal) => match pending(flash, &journal) {
+            Ok(0) => flash.erase(JOURNAL_OFFSET, JOURNAL_END).await,
+            Ok(_) => Ok(()),
+            Err(e) => Err(e),
+        },
+        Err(e) => Err(e),
+    };
+
+    if let Err(e) = result {
+        error!("{}", e);
+    }
+}
+
+async fn scrobble_record(track: &Track, played: Duration) {
+    if played < MIN_PLAYED {
+        return;
+    }
+
+    let timestamp = RTC
+        .lock(|cell| cell.borrow().as_ref().map(Rtc::now))
+        .and_then(|now| now.ok())
+        .filter(|now| now.year() >= MIN_VALID_YEAR)
+        .map(|now| unix_time(&now).saturating_sub(played.as_secs() as u32));
+
+    let scrobble = Scrobble {
+        timestamp,
+        title: track.title.clone(),
+        artist: track.artist.clone(),
+        album: track.album.clone(),
+        played: played.as_secs() as u32,
+        length: track.length.as_secs() as u32,
+    };
+
+    let mut flash = storage_lock().await;
+
+    let Some(flash) = flash.as_mut() else {
+        return;
+    };
+
+    match append_play(flash, &scrobble.encode()).await {
+        Ok(true) => info!("recorded play of {}", scrobble.title.as_str()),
+        Ok(false) => warn!("journal full, dropped play of {}", scrobble.title.as_str()),
+        Err(e) => error!("{}", e),
+    }
+}
+
+/// Handles a request received over SPP, ignoring anything that is not for
+/// the journal.
+pub async fn scrobble_handle(request: &str) -> CommandResult {
+    match request.trim() {
+        "SCROBBLES" => scrobble_export().await,
+        "SCROBBLES CLEAR" => {
+            let pending = scrobble_clear().await;
+            spp_send(&alloc::format!("{{\"count\":{}}}\n", pending)).await
+        }
+        request => {
+            if let Some(Ok(time)) = request
+                .strip_prefix("TIME ")
+                .map(|time| time.trim().parse())
+            {
+                set_time(time);
+            }
+
+            Ok(())
+        }
+    }
+}
+
+async fn scrobble_export() -> CommandResult {
+    let journal = match storage_lock().await.as_mut().map(scan) {
+        Some(Ok(journal)) => journal,
+        Some(Err(e)) => {
+            error!("{}", e);
+            return Ok(());
+        }
+        None => return Ok(()),
+    };
+
+    let mut count = 0;
+
+    // The flash is only locked while reading each record so recording can
+    // carry on while the export waits on the UART.
+    for offset in (journal.cursor..journal.end).step_by(RECORD_SIZE) {
+        let mut record = [0; RECORD_SIZE];
+
+        let result = match storage_lock().await.as_mut() {
+            Some(flash) => flash.blocking_read(offset, &mut record),
+            None => break,
+        };
+
+        if let Err(e) = result {
+            error!("{}", e);
+            break;
+        }
+
+        let Some(scrobble) = Some(&record)
+            .filter(|record| valid(record, RECORD_MAGIC))
+            .and_then(Scrobble::decode)
+        else {
+            continue;
+        };
+
+        spp_send(&scrobble.to_json()).await?;
+        count += 1;
+    }
+
+    // With plays waiting the journal is not erased, so the offset still
+    // points at the same records when the export is cleared.
+    EXPORT_END.lock(|export_end| export_end.set((count > 0).then_some(journal.end)));
+
+    spp_send(&alloc::format!("{{\"count\":{}}}\n", count)).await
+}
+
+/// Marks the plays of the last export as exported. Returns the number of
+/// plays left.
+async fn scrobble_clear() -> usize {
+    let mut flash = storage_lock().await;
+
+    let Some(flash) = flash.as_mut() else {
+        return 0;
+    };
+
+    let result = match EXPORT_END.lock(|export_end| export_end.take()) {
+        Some(export_end) => append_cursor(flash, export_end).await,
+        None => Ok(()),
+    };
+
+    if let Err(e) = result {
+        error!("{}", e);
+    }
+
+    match scan(flash).and_then(|journal| pending(flash, &journal)) {
+        Ok(pending) => pending,
+        Err(e) => {
+            error!("{}", e);
+            0
+        }
+    }
+}
+
+/// Sets the RTC, which keeps UTC, from seconds since the Unix epoch.
+fn set_time(time: u32) {
+    let Some(date_time) = date_time(time) else {
+        return;
+    };
+
+    let result = RTC.lock(|cell| {
+        cell.borrow_mut()
+            .as_mut()
+            .map(|rtc| rtc.set_datetime(date_time))
+    });
+
+    match result {
+        Some(Ok(())) => info!("clock set to {}", time),
+        Some(Err(e)) => error!("{}", e),
+        None => {}
+    }
+}
+
+impl Scrobble {
+    fn encode(&self) -> [u8; RECORD_SIZE] {
+        let mut record = [0xFF; RECORD_SIZE];
+
+        record[..4].copy_from_slice(RECORD_MAGIC);
+        record[4..8].copy_from_slice(&self.timestamp.unwrap_or(0).to_le_bytes());
+        record[8..12].copy_from_slice(&self.played.to_le_bytes());
+        record[12..16].copy_from_slice(&self.length.to_le_bytes());
+
+        let mut offset = 20;
+
+        for (i, (value, max)) in [
+            (&self.title, TITLE_LEN),
+            (&self.artist, ARTIST_LEN),
+            (&self.album, ALBUM_LEN),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let value = &value[..floor_char_boundary(value, max)];
+
+            record[16 + i] = value.len() as u8;
+            record[offset..offset + value.len()].copy_from_slice(value.as_bytes());
+            offset += max;
+        }
+
+        seal(&mut record);
+
+        record
+    }
+
+    fn decode(record: &[u8; RECORD_SIZE]) -> Option<Self> {
+        let word =
+            |offset: usize| u32::from_le_bytes(record[offset..offset + 4].try_into().unwrap());
+
+        let mut offset = 20;
+        let mut fields = [TITLE_LEN, ARTIST_LEN, ALBUM_LEN]
+            .into_iter()
+            .enumerate()
+            .map(|(i, max)| {
+                let len = (record[16 + i] as usize).min(max);
+                let value = str::from_utf8(&record[offset..offset + len]).ok();
+                offset += max;
+                value.map(str::to_string)
+            });
+
+        Some(Self {
+            timestamp: Some(word(4)).filter(|timestamp| *timestamp != 0),
+            played: word(8),
+            length: word(12),
+            title: fields.next()??,
+            artist: fields.next()??,
+            album: fields.next()??,
+        })
+    }
+
+    fn to_json(&self) -> String {
+        let mut json = String::new();
+
+        json.push_str("{\"timestamp\":");
+        match self.timestamp {
+            Some(timestamp) => write!(json, "{}", timestamp).unwrap(),
+            None => json.push_str("null"),
+        }
+        json.push_str(",\"title\":");
+        push_json_str(&mut json, &self.title);
+        json.push_str(",\"artist\":");
+        push_json_str(&mut json, &self.artist);
+        json.push_str(",\"album\":");
+        push_json_str(&mut json, &self.album);
+        writeln!(
+            json,
+            ",\"played\":{},\"length\":{}}}",
+            self.played, self.length
+        )
+        .unwrap();
+
+        json
+    }
+}
+
+fn push_json_str(json: &mut String, value: &str) {
+    json.push('"');
+
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            c if (c as u32) < 0x20 => write!(json, "\\u{:04x}", c as u32).unwrap(),
+            c => json.push(c),
+        }
+    }
+
+    json.push('"');
+}
+
+/// Seconds since the Unix epoch, the RTC keeps UTC.
+fn unix_time(date_time: &DateTime) -> u32 {
+    // Days from civil, see http://howardhinnant.github.io/date_algorithms.html
+    let month = date_time.month() as u32;
+    let year = date_time.year() as u32 - (month <= 2) as u32;
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + date_time.day() as u32 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+
+    days * 86_400
+        + date_time.hour() as u32 * 3_600
+        + date_time.minute() as u32 * 60
+        + date_time.second() as u32
+}
+
+/// Inverse of `unix_time`.
+fn date_time(time: u32) -> Option<DateTime> {
+    // Civil from days, see http://howardhinnant.github.io/date_algorithms.html
+    let days = time / 86_400 + 719_468;
+    let seconds = time % 86_400;
+    let era = days / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + (month <= 2) as u32;
+
+    // 1970-01-01 was a Thursday.
+    let day_of_week = match (time / 86_400 + 3) % 7 {
+        0 => DayOfWeek::Monday,
+        1 => DayOfWeek::Tuesday,
+        2 => DayOfWeek::Wednesday,
+        3 => DayOfWeek::Thursday,
+        4 => DayOfWeek::Friday,
+        5 => DayOfWeek::Saturday,
+        _ => DayOfWeek::Sunday,
+    };
+
+    DateTime::from(
+        year as u16,
+        month as u8,
+        day as u8,
+        day_of_week,
+        (seconds / 3_600) as u8,
+        (seconds / 60 % 60) as u8,
+        (seconds % 60) as u8,
+    )
+    .ok()
+}
+
+fn valid(record: &[u8; RECORD_SIZE], magic: &[u8; 4]) -> bool {
+    &record[..4] == magic && record[CRC_OFFSET..] == crc16(&record[..CRC_OFFSET]).to_le_bytes()
+}
+
+fn seal(record: &mut [u8; RECORD_SIZE]) {
+    let crc = crc16(&record[..CRC_OFFSET]);
+    record[CRC_OFFSET..].copy_from_slice(&crc.to_le_bytes());
+}
+
+struct Journal {
+    /// Offset of the first play not yet exported.
+    cursor: u32,
+    /// Offset after the last record written.
+    end: u32,
+}
+
+fn scan(flash: &mut Flash<'static, Async>) -> Result<Journal, Error> {
+    let mut journal = Journal {
+        cursor: JOURNAL_OFFSET,
+        end: JOURNAL_OFFSET,
+    };
+    let mut record = [0; RECORD_SIZE];
+
+    for offset in (JOURNAL_OFFSET..JOURNAL_END).step_by(RECORD_SIZE) {
+        flash.blocking_read(offset, &mut record)?;
+
+        if record.iter().all(|byte| *byte == 0xFF) {
+            break;
+        }
+
+        journal.end = offset + RECORD_SIZE as u32;
+
+        if valid(&record, CURSOR_MAGIC) {
+            let cursor = u32::from_le_bytes(record[4..8].try_into().unwrap());
+            journal.cursor = journal.cursor.max(cursor.min(offset));
+        }
+    }
+
+    Ok(journal)
+}
+
+/// Number of plays not yet exported.
+fn pending(flash: &mut Flash<'static, Async>, journal: &Journal) -> Result<usize, Error> {
+    let mut count = 0;
+    let mut record = [0; RECORD_SIZE];
+
+    for offset in (journal.cursor..journal.end).step_by(RECORD_SIZE) {
+        flash.blocking_read(offset, &mut record)?;
+
+        if valid(&record, RECORD_MAGIC) {
+            count += 1;
+        }
+    }
+
+    Ok(count)
+}
+
+/// Returns false, leaving the journal as it is, if it is full. Plays are
+/// recorded while listening, so the journal is never erased here.
+async fn append_play(
+    flash: &mut Flash<'static, Async>,
+    record: &[u8; RECORD_SIZE],
+) -> Result<bool, Error> {
+    let journal = scan(flash)?;
+
+    if journal.end >= JOURNAL_END {
+        return Ok(false);
+    }
+
+    flash.write(journal.end, record).await?;
+
+    Ok(true)
+}
+
+async fn append_cursor(flash: &mut Flash<'static, Async>, cursor: u32) -> Result<(), Error> {
+    let journal = scan(flash)?;
+
+    // The plays are exported again next time, services drop duplicates.
+    if journal.end >= JOURNAL_END {
+        warn!("no room to mark plays exported");
+        return Ok(());
+    }
+
+    let mut record = [0xFF; RECORD_SIZE];
+    record[..4].copy_from_slice(CURSOR_MAGIC);
+    record[4..8].copy_from_slice(&cursor.to_le_bytes());
+    seal(&mut record);
+
+    flash.write(journal.end, &record).await
+}
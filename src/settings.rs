@@ -0,0 +1,190 @@
+use alloc::string::{String, ToString};
+use core::str;
+use defmt::{error, Format};
+use embassy_stm32::flash::{Async, Error, Flash};
+
+use crate::{
+    a2dp_setup::AudioInput,
+    crc::crc16,
+    feasycom_protocol::indication::A2dpRole,
+    phonebook::contact_index::{DEFAULT_COUNTRY_CODE, MAX_COUNTRY_CODE},
+    storage::storage_lock,
+    volume::{VOLUME_LIMIT, VOLUME_MAX_LEVEL},
+};
+
+/// Last 128 KiB sector of the STM32F411CE, excluded from `FLASH` in
+/// `memory.x`. Settings are appended as fixed size records so the sector only
+/// needs erasing once it is full. Each record ends in a CRC of the rest, a
+/// record that fails it is skipped in favour of the one before.
+const SETTINGS_OFFSET: u32 = 0x6_0000;
+const SETTINGS_SIZE: u32 = 0x2_0000;
+const RECORD_SIZE: usize = 32;
+const RECORD_MAGIC: &[u8; 4] = b"APS2";
+const CRC_OFFSET: usize = RECORD_SIZE - 2;
+const MAC_LEN: usize = 17;
+const VOLUME_LIMIT_OFFSET: usize = 26;
+const COUNTRY_CODE_OFFSET: usize = 27;
+
+pub async fn settings_load() -> Settings {
+    let mut flash = storage_lock().await;
+
+    let Some(flash) = flash.as_mut() else {
+        return Settings::default();
+    };
+
+    match scan(flash) {
+        Ok((_, record)) => record
+            .and_then(|record| Settings::decode(&record))
+            .unwrap_or_default(),
+        Err(e) => {
+            error!("{}", e);
+            Settings::default()
+        }
+    }
+}
+
+pub async fn settings_store(settings: &Settings) {
+    let mut flash = storage_lock().await;
+
+    let Some(flash) = flash.as_mut() else {
+        return;
+    };
+
+    if let Err(e) = append_record(flash, &settings.encode()).await {
+        error!("{}", e);
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Format)]
+pub struct Settings {
+    pub a2dp_role: A2dpRole,
+    pub a2dp_sink: Option<String>,
+    pub audio_input: AudioInput,
+    pub volume_limit: u8,
+    /// Country code of numbers the phonebook lists in national form.
+    pub country_code: u16,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            a2dp_role: A2dpRole::Sink,
+            a2dp_sink: Default::default(),
+            audio_input: AudioInput::I2s,
+            volume_limit: VOLUME_LIMIT,
+            country_code: DEFAULT_COUNTRY_CODE,
+        }
+    }
+}
+
+impl Settings {
+    fn encode(&self) -> [u8; RECORD_SIZE] {
+        let mut record = [0xFF; RECORD_SIZE];
+
+        record[..4].copy_from_slice(RECORD_MAGIC);
+        record[4] = match self.a2dp_role {
+            A2dpRole::Sink => 0,
+            A2dpRole::Source => 1,
+        };
+        record[5] = match self.audio_input {
+            AudioInput::I2s => 0,
+            AudioInput::Spdif => 1,
+        };
+
+        if let Some(a2dp_sink) = self.a2dp_sink.as_deref().filter(|mac| mac.len() <= MAC_LEN) {
+            record[6] = a2dp_sink.len() as u8;
+            record[8..8 + a2dp_sink.len()].copy_from_slice(a2dp_sink.as_bytes());
+        } else {
+            record[6] = 0;
+        }
+
+        record[VOLUME_LIMIT_OFFSET] = self.volume_limit;
+        record[COUNTRY_CODE_OFFSET..COUNTRY_CODE_OFFSET + 2]
+            .copy_from_slice(&self.country_code.to_le_bytes());
+
+        let crc = crc16(&record[..CRC_OFFSET]);
+        record[CRC_OFFSET..].copy_from_slice(&crc.to_le_bytes());
+
+        record
+    }
+
+    fn decode(record: &[u8; RECORD_SIZE]) -> Option<Self> {
+        let a2dp_sink_len = (record[6] as usize).min(MAC_LEN);
+
+        Some(Self {
+            a2dp_role: match record[4] {
+                1 => A2dpRole::Source,
+                _ => A2dpRole::Sink,
+            },
+            audio_input: match record[5] {
+                1 => AudioInput::Spdif,
+                _ => AudioInput::I2s,
+            },
+            a2dp_sink: match a2dp_sink_len {
+                0 => None,
+                len => Some(str::from_utf8(&record[8..8 + len]).ok()?.to_string()),
+            },
+            volume_limit: match record[VOLUME_LIMIT_OFFSET] {
+                limit @ 0..=VOLUME_MAX_LEVEL => limit,
+                _ => VOLUME_LIMIT,
+            },
+            country_code: match u16::from_le_bytes([
+                record[COUNTRY_CODE_OFFSET],
+                record[COUNTRY_CODE_OFFSET + 1],
+            ]) {
+                country_code @ 1..=MAX_COUNTRY_CODE => country_code,
+                _ => DEFAULT_COUNTRY_CODE,
+            },
+        })
+    }
+}
+
+fn valid(record: &[u8; RECORD_SIZE]) -> bool {
+    &record[..4] == RECORD_MAGIC
+        && record[CRC_OFFSET..] == crc16(&record[..CRC_OFFSET]).to_le_bytes()
+}
+
+/// Returns the offset after the last record written along with the newest
+/// valid record, if any.
+fn scan(flash: &mut Flash<'static, Async>) -> Result<(u32, Option<[u8; RECORD_SIZE]>), Error> {
+    let mut end = SETTINGS_OFFSET;
+    let mut latest = None;
+    let mut record = [0; RECORD_SIZE];
+
+    for offset in (SETTINGS_OFFSET..SETTINGS_OFFSET + SETTINGS_SIZE).step_by(RECORD_SIZE) {
+        flash.blocking_read(offset, &mut record)?;
+
+        if record.iter().all(|byte| *byte == 0xFF) {
+            break;
+        }
+
+        end = offset + RECORD_SIZE as u32;
+
+        if valid(&record) {
+            latest = Some(record);
+        }
+    }
+
+    Ok((end, latest))
+}
+
+async fn append_record(
+    flash: &mut Flash<'static, Async>,
+    record: &[u8; RECORD_SIZE],
+) -> Result<(), Error> {
+    let offset = match scan(flash)? {
+        (_, Some(latest)) if &latest == record => return Ok(()),
+        (end, _) => end,
+    };
+
+    let offset = if offset >= SETTINGS_OFFSET + SETTINGS_SIZE {
+        flash
+            .erase(SETTINGS_OFFSET, SETTINGS_OFFSET + SETTINGS_SIZE)
+            .await?;
+        SETTINGS_OFFSET
+    } else {
+        offset
+    };
+
+    flash.write(offset, record).await
+}
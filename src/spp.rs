@@ -0,0 +1,37 @@
+use crate::{
+    command_queue::{command_send, CommandPriority, CommandResult},
+    feasycom_protocol::command,
+};
+
+/// Largest payload passed to a single `AT+SPPSEND`.
+const SPP_CHUNK: usize = 128;
+
+/// Sends text to the connected SPP peer, split into `AT+SPPSEND` sized
+/// chunks on character boundaries. Each chunk waits for its answer in the
+/// command queue, so the module is never sent more than it can buffer.
+pub async fn spp_send(mut payload: &str) -> CommandResult {
+    while !payload.is_empty() {
+        let (chunk, rest) = payload.split_at(floor_char_boundary(payload, SPP_CHUNK));
+
+        command_send(
+            CommandPriority::Normal,
+            command::SppSend::new().payload(chunk).as_bytes(),
+        )
+        .await?;
+
+        payload = rest;
+    }
+
+    Ok(())
+}
+
+pub fn floor_char_boundary(value: &str, index: usize) -> usize {
+    if index >= value.len() {
+        return value.len();
+    }
+
+    (0..=index)
+        .rev()
+        .find(|i| value.is_char_boundary(*i))
+        .unwrap_or(0)
+}
@@ -0,0 +1,137 @@
+//! Requests from the companion tool, sent over SPP one per line. Each
+//! module handles the requests meant for it and ignores the rest, answering
+//! over SPP. Headphones found while scanning are reported the same way.
+//!
+//! The speaker volume is set with `VOLUME <level>`, and the most it can be
+//! set to, from here or the phone, with `VOLUME LIMIT <level>`. Both are on
+//! the module's 0-15 scale and the limit is kept across restarts.
+//!
+//! `COUNTRY <code>`, e.g. `COUNTRY 44`, sets the country code used to match
+//! caller IDs against phonebook numbers written without one.
+//!
+//! `PAIRING ON|OFF` makes the module discoverable or not, `BLUETOOTH ON|OFF`
+//! switches its radio, ending this link when switched off.
+
+use defmt::{error, warn};
+
+#[cfg(feature = "uart-capture")]
+use crate::uart_capture::uart_capture_handle;
+use crate::{
+    a2dp_source::{a2dp_source_handle, a2dp_source_scan_result},
+    command_queue::{command_send, CommandPriority, CommandResult},
+    feasycom_protocol::command,
+    feasycom_protocol::indication::Indication,
+    indication_bus::{indication_subscribe, IndicationClass},
+    phonebook::contact_index::{contact_index_set_country_code, MAX_COUNTRY_CODE},
+    scrobble::scrobble_handle,
+    settings::{settings_load, settings_store},
+    throughput::throughput_handle,
+    volume::{set_volume, set_volume_limit, VOLUME_MAX_LEVEL},
+};
+
+#[embassy_executor::task]
+pub async fn spp_shell_task() -> ! {
+    let mut indications =
+        indication_subscribe(&[IndicationClass::SppData, IndicationClass::Status]).unwrap();
+
+    loop {
+        let result = match indications.next().await {
+            Ok(Indication::SppData(data)) => spp_shell_handle(data.0.trim()).await,
+            Ok(Indication::ScanResult(scan_result)) => a2dp_source_scan_result(&scan_result).await,
+            Ok(_) => continue,
+            Err(lagged) => {
+                warn!("missed {} indications", lagged.0);
+                continue;
+            }
+        };
+
+        if let Err(e) = result {
+            error!("{}", e);
+        }
+    }
+}
+
+async fn spp_shell_handle(request: &str) -> CommandResult {
+    a2dp_source_handle(request).await?;
+    volume_handle(request).await?;
+    country_code_handle(request).await?;
+    radio_handle(request).await?;
+    throughput_handle(request).await?;
+    scrobble_handle(request).await?;
+
+    #[cfg(feature = "uart-capture")]
+    uart_capture_handle(request).await?;
+
+    Ok(())
+}
+
+async fn volume_handle(request: &str) -> CommandResult {
+    let Some(request) = request.strip_prefix("VOLUME ") else {
+        return Ok(());
+    };
+
+    if let Some(limit) = request.strip_prefix("LIMIT ") {
+        let Ok(limit) = limit.trim().parse::<u8>() else {
+            return Ok(());
+        };
+
+        if limit > VOLUME_MAX_LEVEL {
+            return Ok(());
+        }
+
+        let mut settings = settings_load().await;
+        settings.volume_limit = limit;
+        settings_store(&settings).await;
+
+        set_volume_limit(limit);
+    } else if let Ok(level) = request.trim().parse() {
+        set_volume(level);
+    }
+
+    Ok(())
+}
+
+async fn country_code_handle(request: &str) -> CommandResult {
+    let Some(Ok(country_code)) = request
+        .strip_prefix("COUNTRY ")
+        .map(|country_code| country_code.trim().trim_start_matches('+').parse::<u16>())
+    else {
+        return Ok(());
+    };
+
+    if !(1..=MAX_COUNTRY_CODE).contains(&country_code) {
+        return Ok(());
+    }
+
+    let mut settings = settings_load().await;
+    settings.country_code = country_code;
+    settings_store(&settings).await;
+
+    contact_index_set_country_code(country_code);
+
+    Ok(())
+}
+
+async fn radio_handle(request: &str) -> CommandResult {
+    let command = match request {
+        "BLUETOOTH ON" => command::BtEn::new()
+            .enable_bluetooth(true)
+            .as_bytes()
+            .to_vec(),
+        "BLUETOOTH OFF" => command::BtEn::new()
+            .enable_bluetooth(false)
+            .as_bytes()
+            .to_vec(),
+        "PAIRING ON" => command::Pair::new()
+            .enable_pairing(true)
+            .as_bytes()
+            .to_vec(),
+        "PAIRING OFF" => command::Pair::new()
+            .enable_pairing(false)
+            .as_bytes()
+            .to_vec(),
+        _ => return Ok(()),
+    };
+
+    command_send(CommandPriority::High, &command).await
+}
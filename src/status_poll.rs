@@ -0,0 +1,117 @@
+use defmt::{info, warn, Format};
+use embassy_time::{Duration, Instant};
+
+use crate::{
+    app_state::AppState,
+    command_queue::{CommandArbiter, CommandPriority},
+    feasycom_protocol::{command, indication::Indication},
+    feasycom_state::FeasycomState,
+};
+
+/// How long the module may stay silent before its status is queried, long
+/// enough that a playing track's own `+TRACKSTAT` reports keep it quiet.
+pub const STATUS_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+const AWAITING_A2DP_STAT: u8 = 1 << 0;
+const AWAITING_AVRCP_STAT: u8 = 1 << 1;
+const AWAITING_HFP_STAT: u8 = 1 << 2;
+const AWAITING_TRACK_STAT: u8 = 1 << 3;
+
+/// Queries the module when indications go quiet, in case an unsolicited
+/// report was missed, and compares the answers with the cached state.
+pub struct StatusPoller {
+    interval: Duration,
+    last_traffic: Instant,
+    awaiting: u8,
+}
+
+impl StatusPoller {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            last_traffic: Instant::now(),
+            awaiting: 0,
+        }
+    }
+
+    pub fn deadline(&self) -> Instant {
+        self.last_traffic + self.interval
+    }
+
+    /// Postpones the next poll, called for every line received.
+    pub fn traffic(&mut self, now: Instant) {
+        self.last_traffic = now;
+    }
+
+    pub fn poll(
+        &mut self,
+        command_arbiter: &mut CommandArbiter,
+        state: FeasycomState,
+        now: Instant,
+    ) {
+        self.last_traffic = now;
+
+        // Until configured the module is either busy or not answering at all,
+        // which the state machine's own timeouts take care of.
+        if matches!(
+            state,
+            FeasycomState::PoweredOff | FeasycomState::Booting | FeasycomState::Configuring
+        ) {
+            return;
+        }
+
+        info!(
+            "no indications for {}s, polling status",
+            self.interval.as_secs()
+        );
+
+        // `AT+STAT` answers with the status of every profile.
+        self.awaiting = AWAITING_A2DP_STAT | AWAITING_AVRCP_STAT | AWAITING_HFP_STAT;
+
+        command_arbiter.post(CommandPriority::Low, command::A2dpStat::new().as_bytes());
+        command_arbiter.post(CommandPriority::Low, command::Stat::new().as_bytes());
+
+        if matches!(state, FeasycomState::Connected | FeasycomState::Streaming) {
+            self.awaiting |= AWAITING_TRACK_STAT;
+
+            command_arbiter.post(CommandPriority::Low, command::TrackStat::new().as_bytes());
+        }
+    }
+
+    /// Logs where an answer to a poll disagrees with the cached state, the
+    /// indication itself is reduced as usual which brings the state back in
+    /// line. Must be called before the indication is reduced.
+    pub fn reconcile(&mut self, indication: &Indication, app_state: &AppState) {
+        match indication {
+            Indication::A2dpStat(a2dp_stat) if self.take(AWAITING_A2DP_STAT) => {
+                warn_missed("a2dp", a2dp_stat, &app_state.device.a2dp_stat);
+            }
+            Indication::AvrcpStat(avrcp_stat) if self.take(AWAITING_AVRCP_STAT) => {
+                warn_missed("avrcp", avrcp_stat, &app_state.device.avrcp_stat);
+            }
+            Indication::HfpStat(hfp_stat) if self.take(AWAITING_HFP_STAT) => {
+                warn_missed("hfp", hfp_stat, &app_state.device.hfp_stat);
+            }
+            Indication::TrackStat(track_stat) if self.take(AWAITING_TRACK_STAT) => {
+                warn_missed(
+                    "play",
+                    &track_stat.play_stat,
+                    &app_state.playback.clock.play_stat(),
+                );
+            }
+            _ => {}
+        }
+    }
+
+    fn take(&mut self, awaiting: u8) -> bool {
+        let was_awaiting = self.awaiting & awaiting != 0;
+        self.awaiting &= !awaiting;
+        was_awaiting
+    }
+}
+
+fn warn_missed<T: PartialEq + Format>(status: &str, reported: &T, cached: &T) {
+    if reported != cached {
+        warn!("missed {} status {}, cached {}", status, reported, cached);
+    }
+}
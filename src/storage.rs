@@ -0,0 +1,23 @@
+use embassy_stm32::flash::{Async, Flash};
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::mutex::{Mutex, MutexGuard};
+
+/// The internal flash, shared by everything persisted to the sectors excluded
+/// from `FLASH` in `memory.x`. The F411 has a single bank, so instruction
+/// fetch stalls while it is busy: erasing a 128 KiB sector stops everything
+/// for 1-2 s, long enough for the module UART's RX ring to overrun. Sectors
+/// are only erased while the module link is idle, or for settings, after
+/// thousands of changes.
+static FLASH: Mutex<ThreadModeRawMutex, Option<Flash<'static, Async>>> = Mutex::new(None);
+
+pub type StorageGuard = MutexGuard<'static, ThreadModeRawMutex, Option<Flash<'static, Async>>>;
+
+pub async fn storage_init(flash: Flash<'static, Async>) {
+    *FLASH.lock().await = Some(flash);
+}
+
+/// Waits for exclusive access to the flash, which is `None` before
+/// `storage_init`.
+pub async fn storage_lock() -> StorageGuard {
+    FLASH.lock().await
+}
@@ -0,0 +1,137 @@
+use core::{cell::RefCell, future::poll_fn, task::Poll};
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::waitqueue::MultiWakerRegistration;
+
+use crate::TaskRawMutex;
+
+struct StoreState<T, const N: usize> {
+    value: T,
+    version: u32,
+    wakers: MultiWakerRegistration<N>,
+}
+
+/// Holds the latest value of `T`. Writers never block, readers can access the
+/// value synchronously or subscribe to be woken whenever it changes. `N` is
+/// the number of subscribers that can wait at the same time without causing
+/// spurious wake ups.
+pub struct Store<T, const N: usize> {
+    state: Mutex<TaskRawMutex, RefCell<StoreState<T, N>>>,
+}
+
+impl<T, const N: usize> Store<T, N> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            state: Mutex::new(RefCell::new(StoreState {
+                value,
+                version: 1,
+                wakers: MultiWakerRegistration::new(),
+            })),
+        }
+    }
+
+    pub fn with<R>(&self, f: impl FnOnce(&T) -> R) -> R {
+        self.state.lock(|state| f(&state.borrow().value))
+    }
+
+    /// Modifies the value in place, subscribers are only notified when `f`
+    /// reports a change.
+    pub fn update(&self, f: impl FnOnce(&mut T) -> bool) {
+        self.state.lock(|state| {
+            let mut state = state.borrow_mut();
+
+            if f(&mut state.value) {
+                state.version = state.version.wrapping_add(1);
+                state.wakers.wake();
+            }
+        });
+    }
+
+    /// The first call to `changed` on a new subscriber resolves immediately
+    /// with the current value.
+    pub fn subscribe(&self) -> StoreSubscriber<'_, T, N> {
+        StoreSubscriber {
+            store: self,
+            version: 0,
+        }
+    }
+
+    /// Like `subscribe`, but only resolves when the part of the value picked
+    /// by `select` changes. The part is picked under the lock, so only it is
+    /// cloned.
+    pub fn select<U, F>(&self, select: F) -> StoreSelector<'_, T, U, F, N>
+    where
+        U: PartialEq + Clone,
+        F: Fn(&T) -> U,
+    {
+        StoreSelector {
+            store: self,
+            version: 0,
+            select,
+            last: None,
+        }
+    }
+}
+
+pub struct StoreSubscriber<'a, T, const N: usize> {
+    store: &'a Store<T, N>,
+    version: u32,
+}
+
+impl<'a, T: Clone, const N: usize> StoreSubscriber<'a, T, N> {
+    pub async fn changed(&mut self) -> T {
+        poll_fn(|cx| {
+            self.store.state.lock(|state| {
+                let mut state = state.borrow_mut();
+
+                if state.version != self.version {
+                    self.version = state.version;
+                    return Poll::Ready(state.value.clone());
+                }
+
+                state.wakers.register(cx.waker());
+                Poll::Pending
+            })
+        })
+        .await
+    }
+}
+
+pub struct StoreSelector<'a, T, U, F, const N: usize> {
+    store: &'a Store<T, N>,
+    version: u32,
+    select: F,
+    last: Option<U>,
+}
+
+impl<'a, T, U, F, const N: usize> StoreSelector<'a, T, U, F, N>
+where
+    U: PartialEq + Clone,
+    F: Fn(&T) -> U,
+{
+    pub fn get(&self) -> U {
+        self.store.with(&self.select)
+    }
+
+    pub async fn changed(&mut self) -> U {
+        poll_fn(|cx| {
+            self.store.state.lock(|state| {
+                let mut state = state.borrow_mut();
+
+                if state.version != self.version {
+                    self.version = state.version;
+
+                    let selected = (self.select)(&state.value);
+
+                    if self.last.as_ref() != Some(&selected) {
+                        self.last = Some(selected.clone());
+                        return Poll::Ready(selected);
+                    }
+                }
+
+                state.wakers.register(cx.waker());
+                Poll::Pending
+            })
+        })
+        .await
+    }
+}
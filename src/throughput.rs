@@ -0,0 +1,244 @@
+//! Throughput mode, where the module stops framing SPP data as `+SPPDATA`
+//! indications and forwards bytes verbatim between the UART and the peer.
+//!
+//! Sending `THROUGHPUT <seconds>` over SPP switches to it and echoes back
+//! everything the peer sends for that long, to measure the link, before
+//! returning to AT mode.
+
+use defmt::{error, info, warn};
+use embassy_futures::select::{select, select3, Either, Either3};
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::pipe::Pipe;
+use embassy_sync::signal::Signal;
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+
+use crate::{
+    app_state::app_state_with,
+    command_queue::{AtError, CommandResult},
+    feasycom_bluetooth::{FeasycomBluetoothRx, FeasycomBluetoothTx},
+    feasycom_protocol::{
+        command,
+        indication::{Indication, SppStat},
+    },
+};
+
+const PIPE_SIZE: usize = 256;
+
+/// How long the module has to answer the commands switching modes.
+const ANSWER_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long a request waits to hear whether throughput mode was entered,
+/// covering both answers and a command still being written.
+const ENTER_TIMEOUT: Duration = Duration::from_secs(3);
+
+const MAX_ECHO_TIME: u64 = 600;
+
+/// Bytes received from the SPP peer while in throughput mode.
+pub static THROUGHPUT_RX_PIPE: Pipe<ThreadModeRawMutex, PIPE_SIZE> = Pipe::new();
+
+/// Bytes to send to the SPP peer while in throughput mode.
+pub static THROUGHPUT_TX_PIPE: Pipe<ThreadModeRawMutex, PIPE_SIZE> = Pipe::new();
+
+static THROUGHPUT_REQUEST_SIGNAL: Signal<ThreadModeRawMutex, bool> = Signal::new();
+
+/// Whether an enter request switched the module to throughput mode.
+static THROUGHPUT_ENTERED_SIGNAL: Signal<ThreadModeRawMutex, CommandResult> = Signal::new();
+
+pub fn throughput_mode_enter() {
+    THROUGHPUT_REQUEST_SIGNAL.signal(true);
+}
+
+pub fn throughput_mode_exit() {
+    THROUGHPUT_REQUEST_SIGNAL.signal(false);
+}
+
+pub async fn throughput_request_get() -> bool {
+    THROUGHPUT_REQUEST_SIGNAL.wait().await
+}
+
+/// Parses an SPP request, ignoring those meant for other modules.
+pub async fn throughput_handle(request: &str) -> CommandResult {
+    let Some(Ok(seconds)) = request
+        .strip_prefix("THROUGHPUT ")
+        .map(|seconds| seconds.trim().parse::<u64>())
+    else {
+        return Ok(());
+    };
+
+    THROUGHPUT_ENTERED_SIGNAL.reset();
+    throughput_mode_enter();
+
+    if let Err(e) = with_timeout(ENTER_TIMEOUT, THROUGHPUT_ENTERED_SIGNAL.wait())
+        .await
+        .unwrap_or(Err(AtError::Timeout))
+    {
+        // Not taken up in time, so must not be taken up later.
+        throughput_mode_exit();
+        return Err(e);
+    }
+
+    let deadline = Instant::now() + Duration::from_secs(seconds.min(MAX_ECHO_TIME));
+    let mut buf = [0u8; PIPE_SIZE / 2];
+
+    while let Either::First(len) =
+        select(THROUGHPUT_RX_PIPE.read(&mut buf), Timer::at(deadline)).await
+    {
+        THROUGHPUT_TX_PIPE.write_all(&buf[..len]).await;
+    }
+
+    throughput_mode_exit();
+
+    Ok(())
+}
+
+/// Bridges the UART and the throughput pipes until an exit is requested, then
+/// escapes back to AT mode. Whether the mode was entered is reported to the
+/// request first.
+pub async fn throughput_run(
+    feasycom_bluetooth_tx: &mut FeasycomBluetoothTx<'_>,
+    feasycom_bluetooth_rx: &mut FeasycomBluetoothRx<'_>,
+) {
+    let entered = if app_state_with(|app_state| app_state.data.spp_stat) != SppStat::Connected {
+        warn!("no SPP peer to enter throughput mode with");
+        Err(AtError::NotReady)
+    } else if feasycom_bluetooth_tx.unanswered() > 0 {
+        // The answers to the mode switch are told apart from others by
+        // order, so nothing else may be waiting for one.
+        warn!("commands in flight, not entering throughput mode");
+        Err(AtError::NotReady)
+    } else {
+        enter(feasycom_bluetooth_tx, feasycom_bluetooth_rx).await
+    };
+
+    THROUGHPUT_ENTERED_SIGNAL.signal(entered);
+
+    if let Err(e) = entered {
+        error!("{}", e);
+        return;
+    }
+
+    info!("entered throughput mode");
+
+    THROUGHPUT_RX_PIPE.clear();
+    THROUGHPUT_TX_PIPE.clear();
+
+    let mut rx_buf = [0u8; PIPE_SIZE / 2];
+    let mut tx_buf = [0u8; PIPE_SIZE / 2];
+
+    loop {
+        match select3(
+            feasycom_bluetooth_rx.read_raw(&mut rx_buf),
+            THROUGHPUT_TX_PIPE.read(&mut tx_buf),
+            throughput_request_get(),
+        )
+        .await
+        {
+            Either3::First(Ok(len)) => {
+                // Waiting for a reader would stop the exit request being seen,
+                // so bytes nobody reads in time are dropped.
+                let written = THROUGHPUT_RX_PIPE.try_write(&rx_buf[..len]).unwrap_or(0);
+
+                if written < len {
+                    warn!("dropped {} bytes", len - written);
+                }
+            }
+            Either3::First(Err(e)) => error!("{}", e),
+            Either3::Second(len) => {
+                if let Err(e) = feasycom_bluetooth_tx.write_raw(&tx_buf[..len]).await {
+                    error!("{}", e);
+                }
+            }
+            Either3::Third(true) => {}
+            Either3::Third(false) => break,
+        }
+    }
+
+    if let Err(e) = exit(feasycom_bluetooth_tx, feasycom_bluetooth_rx).await {
+        error!("{}", e);
+    }
+
+    info!("exited throughput mode");
+}
+
+async fn enter(
+    feasycom_bluetooth_tx: &mut FeasycomBluetoothTx<'_>,
+    feasycom_bluetooth_rx: &mut FeasycomBluetoothRx<'_>,
+) -> CommandResult {
+    command_answered(
+        feasycom_bluetooth_tx,
+        feasycom_bluetooth_rx,
+        command::TpMode::new()
+            .enable_throughput_mode(true)
+            .as_bytes(),
+    )
+    .await?;
+    command_answered(
+        feasycom_bluetooth_tx,
+        feasycom_bluetooth_rx,
+        command::CloseAt::new().as_bytes(),
+    )
+    .await?;
+
+    // Anything framed so far is AT traffic, from here on bytes are raw.
+    feasycom_bluetooth_rx.clear();
+
+    Ok(())
+}
+
+async fn exit(
+    feasycom_bluetooth_tx: &mut FeasycomBluetoothTx<'_>,
+    feasycom_bluetooth_rx: &mut FeasycomBluetoothRx<'_>,
+) -> CommandResult {
+    feasycom_bluetooth_tx.escape_throughput_mode().await?;
+
+    // Raw bytes received before the escape took effect are not lines.
+    feasycom_bluetooth_rx.clear();
+
+    command_answered(
+        feasycom_bluetooth_tx,
+        feasycom_bluetooth_rx,
+        command::TpMode::new()
+            .enable_throughput_mode(false)
+            .as_bytes(),
+    )
+    .await
+}
+
+/// Writes a command and waits for its answer, with nothing else in flight
+/// the next `OK` or `ERROR` is the one. Indications arriving meanwhile are
+/// dropped.
+async fn command_answered(
+    feasycom_bluetooth_tx: &mut FeasycomBluetoothTx<'_>,
+    feasycom_bluetooth_rx: &mut FeasycomBluetoothRx<'_>,
+    command: &[u8],
+) -> CommandResult {
+    feasycom_bluetooth_tx.write(command).await?;
+
+    let deadline = Instant::now() + ANSWER_TIMEOUT;
+
+    loop {
+        let line = match select(feasycom_bluetooth_rx.read(), Timer::at(deadline)).await {
+            Either::First(Ok(line)) => line,
+            Either::First(Err(e)) => {
+                error!("{}", e);
+                continue;
+            }
+            Either::Second(()) => {
+                feasycom_bluetooth_tx.forget_unanswered(1);
+                return Err(AtError::Timeout);
+            }
+        };
+
+        match Indication::try_from(line.as_slice()) {
+            Ok(Indication::Ok) => {
+                feasycom_bluetooth_tx.answered();
+                return Ok(());
+            }
+            Ok(Indication::Err) => {
+                feasycom_bluetooth_tx.answered();
+                return Err(AtError::Rejected);
+            }
+            _ => {}
+        }
+    }
+}
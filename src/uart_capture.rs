@@ -0,0 +1,196 @@
+//! Record of the raw bytes exchanged with the module, built with the
+//! `uart-capture` feature, for reproducing field issues with `uart_replay`.
+//!
+//! Bytes are kept in a RAM ring as they are read from and written to the
+//! UART, the oldest dropped once it is full. Sending `CAPTURE` over SPP
+//! exports the ring, oldest first, with one line per read or write:
+//!
+//! ```text
+//! 15342 rx 2b565245523d4653432d42543130333642...0d0a
+//! 15360 tx 41542b5645520d0a
+//! ```
+//!
+//! The fields are the uptime in milliseconds, `rx` for bytes received from
+//! the module or `tx` for bytes sent to it, and the bytes in lowercase hex.
+//! The export ends with a `# N records` line. `CAPTURE LOG` logs the same
+//! lines through defmt instead, where the replay ignores the log prefix, and
+//! `CAPTURE CLEAR` empties the ring. Capturing pauses during an export so
+//! the export does not record itself.
+
+use alloc::string::String;
+use core::{cell::RefCell, fmt::Write};
+use defmt::{info, Format};
+use embassy_sync::blocking_mutex::{raw::ThreadModeRawMutex, Mutex};
+use embassy_time::Instant;
+
+use crate::{command_queue::CommandResult, spp::spp_send};
+
+const CAPTURE_SIZE: usize = 4096;
+
+/// Uptime in milliseconds as a little endian `u32`, the direction and the
+/// number of bytes that follow.
+const RECORD_HEADER: usize = 6;
+
+/// Longest run of bytes in one record, longer reads and writes are split.
+const RECORD_DATA: usize = u8::MAX as usize;
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Format)]
+pub enum Direction {
+    Rx,
+    Tx,
+}
+
+impl Direction {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Rx => "rx",
+            Self::Tx => "tx",
+        }
+    }
+}
+
+struct CaptureRing {
+    buf: [u8; CAPTURE_SIZE],
+    start: usize,
+    len: usize,
+    paused: bool,
+}
+
+impl CaptureRing {
+    const fn new() -> Self {
+        Self {
+            buf: [0; CAPTURE_SIZE],
+            start: 0,
+            len: 0,
+            paused: false,
+        }
+    }
+
+    fn byte(&self, offset: usize) -> u8 {
+        self.buf[(self.start + offset) % CAPTURE_SIZE]
+    }
+
+    fn push(&mut self, timestamp: u32, direction: Direction, data: &[u8]) {
+        let size = RECORD_HEADER + data.len();
+
+        while CAPTURE_SIZE - self.len < size {
+            let oldest = RECORD_HEADER + self.byte(5) as usize;
+            self.start = (self.start + oldest) % CAPTURE_SIZE;
+            self.len -= oldest;
+        }
+
+        let mut header = [0; RECORD_HEADER];
+        header[..4].copy_from_slice(&timestamp.to_le_bytes());
+        header[4] = direction as u8;
+        header[5] = data.len() as u8;
+
+        for byte in header.iter().chain(data) {
+            self.buf[(self.start + self.len) % CAPTURE_SIZE] = *byte;
+            self.len += 1;
+        }
+    }
+
+    /// Formats the record at `offset` as an export line, returning the line
+    /// and the offset of the next record.
+    fn line(&self, offset: usize) -> Option<(String, usize)> {
+        if offset >= self.len {
+            return None;
+        }
+
+        let timestamp = u32::from_le_bytes([
+            self.byte(offset),
+            self.byte(offset + 1),
+            self.byte(offset + 2),
+            self.byte(offset + 3),
+        ]);
+        let direction = match self.byte(offset + 4) {
+            0 => Direction::Rx,
+            _ => Direction::Tx,
+        };
+        let len = self.byte(offset + 5) as usize;
+
+        let mut line = String::with_capacity(16 + 2 * len);
+        write!(line, "{} {} ", timestamp, direction.as_str()).unwrap();
+        for i in 0..len {
+            write!(line, "{:02x}", self.byte(offset + RECORD_HEADER + i)).unwrap();
+        }
+        line.push('\n');
+
+        Some((line, offset + RECORD_HEADER + len))
+    }
+}
+
+static CAPTURE: Mutex<ThreadModeRawMutex, RefCell<CaptureRing>> =
+    Mutex::new(RefCell::new(CaptureRing::new()));
+
+/// Records bytes read from or written to the module.
+pub fn uart_capture_record(direction: Direction, data: &[u8]) {
+    let timestamp = Instant::now().as_millis() as u32;
+
+    CAPTURE.lock(|cell| {
+        let mut capture = cell.borrow_mut();
+
+        if capture.paused {
+            return;
+        }
+
+        for chunk in data.chunks(RECORD_DATA) {
+            capture.push(timestamp, direction, chunk);
+        }
+    })
+}
+
+pub fn uart_capture_clear() {
+    CAPTURE.lock(|cell| {
+        let mut capture = cell.borrow_mut();
+        capture.start = 0;
+        capture.len = 0;
+    })
+}
+
+/// Handles a request received over SPP, ignoring anything that is not for
+/// the capture.
+pub async fn uart_capture_handle(request: &str) -> CommandResult {
+    match request.trim() {
+        "CAPTURE" => {
+            set_paused(true);
+            let result = uart_capture_export().await;
+            set_paused(false);
+            result
+        }
+        "CAPTURE LOG" => {
+            let mut offset = 0;
+
+            while let Some((line, next)) = CAPTURE.lock(|cell| cell.borrow().line(offset)) {
+                info!("{=str}", line.trim_end());
+                offset = next;
+            }
+
+            Ok(())
+        }
+        "CAPTURE CLEAR" => {
+            uart_capture_clear();
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+async fn uart_capture_export() -> CommandResult {
+    let mut offset = 0;
+    let mut count = 0;
+
+    // The ring is only locked while formatting each record, which is left
+    // unchanged as capturing is paused.
+    while let Some((line, next)) = CAPTURE.lock(|cell| cell.borrow().line(offset)) {
+        spp_send(&line).await?;
+        offset = next;
+        count += 1;
+    }
+
+    spp_send(&alloc::format!("# {} records\n", count)).await
+}
+
+fn set_paused(paused: bool) {
+    CAPTURE.lock(|cell| cell.borrow_mut().paused = paused);
+}
@@ -0,0 +1,36 @@
+//! Replay transport, built with the `uart-replay` feature in place of the
+//! module. Feeds a capture exported by `uart_capture` through `replay`, at
+//! the pace it was captured, so a field issue can be reproduced and checked
+//! on the device without the module fitted.
+//!
+//! The capture is compiled in from the file named by the
+//! `UART_REPLAY_CAPTURE` environment variable.
+
+use defmt::warn;
+use embassy_time::{Duration, Instant, Timer};
+
+use crate::replay::{replay_records, Replay};
+
+#[embassy_executor::task]
+pub async fn uart_replay_task(capture: &'static str) {
+    let mut records = replay_records(capture).peekable();
+
+    let start = Instant::now();
+    let first = records.peek().map_or(0, |record| record.timestamp);
+    let mut replay = Replay::new(start);
+
+    for record in records {
+        let at = start + Duration::from_millis(record.timestamp.wrapping_sub(first) as u64);
+
+        if let Some(deadline) = replay.deadline().filter(|deadline| *deadline < at) {
+            Timer::at(deadline).await;
+        }
+
+        replay.advance(at);
+
+        Timer::at(at).await;
+        replay.push(&record, at);
+    }
+
+    warn!("replay finished in {}", replay.state());
+}
@@ -0,0 +1,144 @@
+use defmt::Format;
+use embassy_sync::signal::Signal;
+
+use crate::TaskRawMutex;
+
+/// The module reports the speaker volume on a 0-15 scale.
+pub const VOLUME_MAX_LEVEL: u8 = 15;
+
+/// Default upper bound applied to every requested level, and enforced
+/// against levels set from the phone, to protect the listener's hearing.
+pub const VOLUME_LIMIT: u8 = 12;
+
+static VOLUME_REQUEST_SIGNAL: Signal<TaskRawMutex, VolumeRequest> = Signal::new();
+
+pub fn set_volume(level: u8) {
+    VOLUME_REQUEST_SIGNAL.signal(VolumeRequest::Level(level));
+}
+
+pub fn set_volume_limit(limit: u8) {
+    VOLUME_REQUEST_SIGNAL.signal(VolumeRequest::Limit(limit));
+}
+
+pub async fn volume_request() -> VolumeRequest {
+    VOLUME_REQUEST_SIGNAL.wait().await
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Format)]
+pub enum VolumeRequest {
+    Level(u8),
+    Limit(u8),
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Format)]
+pub enum VolumeStep {
+    Query,
+    Increase,
+    Decrease,
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Format)]
+pub struct Volume {
+    level: Option<u8>,
+    target: Option<u8>,
+    limit: u8,
+}
+
+impl Volume {
+    pub const fn new(limit: u8) -> Self {
+        Self {
+            level: None,
+            target: None,
+            limit: if limit < VOLUME_MAX_LEVEL {
+                limit
+            } else {
+                VOLUME_MAX_LEVEL
+            },
+        }
+    }
+
+    pub fn level(&self) -> Option<u8> {
+        self.level
+    }
+
+    pub fn limit(&self) -> u8 {
+        self.limit
+    }
+
+    /// Lowering the limit under the current level steps the volume down.
+    pub fn set_limit(&mut self, limit: u8) {
+        self.limit = limit.min(VOLUME_MAX_LEVEL);
+        self.target = self.target.map(|target| target.min(self.limit));
+    }
+
+    pub fn set_target(&mut self, level: u8) {
+        self.target = Some(level.min(self.limit));
+    }
+
+    pub fn update(&mut self, level: u8) {
+        self.level = Some(level);
+
+        if self.target == Some(level) {
+            self.target = None;
+        }
+    }
+
+    /// The command needed to move towards the target level, or back under the
+    /// limit, one step at a time as `AT+SPKVOL` only offers `+` and `-`.
+    pub fn next_step(&self) -> Option<VolumeStep> {
+        let Some(level) = self.level else {
+            return Some(VolumeStep::Query);
+        };
+
+        if level > self.limit {
+            return Some(VolumeStep::Decrease);
+        }
+
+        match self.target {
+            Some(target) if target > level => Some(VolumeStep::Increase),
+            Some(target) if target < level => Some(VolumeStep::Decrease),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn steps_towards_the_target() {
+        let mut volume = Volume::new(VOLUME_LIMIT);
+        assert_eq!(volume.next_step(), Some(VolumeStep::Query));
+
+        volume.update(5);
+        volume.set_target(7);
+        assert_eq!(volume.next_step(), Some(VolumeStep::Increase));
+
+        volume.update(6);
+        volume.update(7);
+        assert_eq!(volume.next_step(), None);
+    }
+
+    #[test]
+    fn targets_are_capped_at_the_limit() {
+        let mut volume = Volume::new(VOLUME_LIMIT);
+        volume.update(VOLUME_LIMIT);
+        volume.set_target(VOLUME_MAX_LEVEL);
+
+        assert_eq!(volume.next_step(), None);
+    }
+
+    #[test]
+    fn lowering_the_limit_steps_down() {
+        let mut volume = Volume::new(VOLUME_LIMIT);
+        volume.update(10);
+        volume.set_limit(8);
+
+        assert_eq!(volume.limit(), 8);
+        assert_eq!(volume.next_step(), Some(VolumeStep::Decrease));
+
+        volume.update(8);
+        assert_eq!(volume.next_step(), None);
+    }
+}